@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use crate::error::{Error, Result};
+use crate::expand_files::{expand_file, show_lines};
+use crate::header::Header;
+use crate::index::{open_index, IndexFile};
+use crate::reader::ArchiveReader;
+use crate::verify::verify_chunks;
+
+// an archive opened for reading, with its index embedded or separate
+pub struct ArchiveFile {
+    pub(crate) name: String, // path of the archive, search results of several archives are labelled with it
+    pub(crate) file: File,
+    pub(crate) index: IndexFile,
+    pub(crate) checksums: bool, // check the checksums of every chunk read
+}
+
+impl ArchiveFile {
+    // a single-file .mms archive with embedded index
+    pub fn open(path:&Path) -> Result<ArchiveFile> {
+        let file = File::open(path)?;
+        let index = IndexFile::open_embedded(&file)?.ok_or_else(|| Error::InvalidInput(
+            format!("{} has no embedded index, open it with its index file", path.display())))?;
+        Ok(ArchiveFile { name: path.display().to_string(), file, index, checksums: false })
+    }
+
+    // an archive with the index embedded in it, otherwise at index_path
+    pub fn open_with_index(path:&Path, index_path:&Path) -> Result<ArchiveFile> {
+        let file = File::open(path)?;
+        let index = open_index(&file, index_path)?;
+        Ok(ArchiveFile { name: path.display().to_string(), file, index, checksums: false })
+    }
+
+    // check the compressed and original bytes of every chunk read against their checksums
+    // by reader, expand, show_lines and searches
+    pub fn verify_checksums(mut self, checksums:bool) -> ArchiveFile {
+        self.checksums = checksums;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn header(&self) -> Header {
+        self.index.header
+    }
+
+    // names of the original files of the manifest, in archive order
+    pub fn file_names(&self) -> Vec<String> {
+        self.index.list().files.iter().map(|f| f.name.to_string()).collect()
+    }
+
+    // original bytes of the archive, files of the manifest are concatenated
+    pub fn reader(&self) -> Result<ArchiveReader> {
+        Ok(ArchiveReader::new(self.file.try_clone()?, &self.index).with_checksums(self.checksums))
+    }
+
+    // write the original bytes to out, returns their length
+    pub fn expand(&self, out:&mut impl Write) -> Result<u64> {
+        expand_file(&self.file, &self.index, self.checksums, out)
+    }
+
+    // write the lines from line - context to line + context of a file of the manifest
+    pub fn show_lines(&self, file:u32, line:u64, context:u64, out:&mut impl Write) -> Result<()> {
+        show_lines(&self.file, &self.index, self.checksums, file, line, context, out)
+    }
+
+    // read every chunk and check it against the index: checksums, sizes, line counts and n-gram bitmaps
+    // returns the problems found, an empty list when the archive is sound
+    pub fn verify(&self) -> Result<Vec<Error>> {
+        verify_chunks(&self.file, &self.index)
+    }
+}
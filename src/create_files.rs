@@ -1,77 +1,898 @@
-use std::process;
 use std::fs;
-use std::io::{Read, Write};
-use crate::index::{Index, ListofIndex, hash_3_to_2, fill_index};
-use lz4_flex::block::{compress_into,get_maximum_output_size};
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use crate::error::{Error, Result};
+use crate::index::{checksum, expand_chunk, Index, ListofIndex, FileEntry, IndexFile, Ngrams};
+use rkyv::Deserialize;
+use crate::header::{ChunkFrame, Header, Footer, HASH_3_TO_2, HASH_MULTIPLY, HEADER_SIZE};
+use crate::timestamp::{continues_record, latest_of, time_range, TimeFormat};
+use crate::repair::{rechunk_archive, reindex_archive, repair_archive, Repaired};
+use memchr::memchr_iter;
+use lz4_flex::block::{compress_into, get_maximum_output_size};
 
-// create index
-pub fn create_files(source:&mut fs::File, target:&mut fs::File,  index:&mut fs::File,chunk_size:usize) -> std::io::Result<()> {
-    let mut read_buff: Vec<u8> = vec![0;chunk_size]; // source reading chunk buffer
-    let mut bits:Vec::<bool> = vec![true;65536]; // hash hold bit vector
-    let mut nread :usize = 0;
+// decide where the chunk ends in buf
+// the chunk is cut after the last delimiter, a record longer than buf is cut at the end of buf
+fn chunk_end(buf:&[u8], delimiter:Option<u8>, last:bool) -> usize {
+    if last {
+        return buf.len();
+    }
+    match delimiter.and_then(|d| buf.iter().rposition(|x| *x==d)) {
+        Some(pos) => pos + 1,
+        None => buf.len(),
+    }
+}
 
-    let mut indexies :ListofIndex = ListofIndex { n: 0, indexies: Vec::new() };
+// a source file and the name it is recorded with in the manifest
+pub struct Source {
+    pub name: String,
+    pub file: fs::File,
+}
 
-    let mut compressed_buffer:Vec<u8> = vec![0;get_maximum_output_size(chunk_size)];
-    let mut compress_offset:u64 = 0;
-    
-    loop {
-        for item in bits.iter_mut() {
-            *item = false;
+impl Source {
+    pub fn open(path:&Path) -> Result<Source> {
+        Ok(Source { name: path.display().to_string(), file: fs::File::open(path)? })
+    }
+
+    // manifest entry with nothing archived yet
+    fn entry(&self) -> Result<FileEntry> {
+        Ok(FileEntry { name: self.name.clone(), size: 0, mtime: self.file.metadata()?.mtime() })
+    }
+}
+
+// part of a source file to be cut into chunks from its current position
+pub(crate) struct Segment<'a> {
+    pub(crate) file: &'a mut (dyn Read + Send),
+    pub(crate) id: u32,            // position in the manifest
+    pub(crate) offset: u64,        // current position of file
+    pub(crate) mtime: i64,         // modification time of file, the reference time of syslog lines without year
+    pub(crate) prev_tail: Vec<u8>, // end of the chunk before the first one when it was cut inside a record
+}
+
+// a chunk cut by the reader, hashed and compressed by a worker
+struct RawChunk {
+    seq: usize,
+    file: u32,
+    file_offset: u64,
+    mtime: i64,
+    data: Vec<u8>,      // chunk bytes followed by lookahead bytes
+    lookahead: usize,   // bytes after the chunk hashed into it when the chunk is cut inside a record
+    prev_tail: Vec<u8>, // end of the previous chunk when it was cut inside a record
+}
+
+// a chunk ready to be written, its offset is set by the writer
+struct PackedChunk {
+    seq: usize,
+    index: Index,
+    compressed: Vec<u8>,
+    continued: bool, // starts with lines without timestamp, which have the last time of the chunk before
+}
+
+// how source is cut into chunks and indexed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Chunking {
+    chunk_size: usize,
+    delimiter: Option<u8>,
+    fold: bool,
+    time_format: Option<TimeFormat>,
+    ngrams: Ngrams,
+    threads: usize,
+    partial_tail: bool, // archive a last record without delimiter, append leaves it for the next run
+}
+
+impl Chunking {
+    pub(crate) fn new(header:Header, threads:usize, partial_tail:bool) -> Chunking {
+        Chunking {
+            chunk_size: header.chunk_size as usize,
+            delimiter: header.delimiter,
+            fold: header.folded,
+            time_format: header.time_format,
+            ngrams: header.ngrams(),
+            threads,
+            partial_tail,
         }
-        let mut remains = chunk_size;
-        let mut read_count = 0;
+    }
+}
+
+// read a segment and cut it into chunks aligned to delimiter, chunks are numbered from seq on
+// returns false when the writer stopped
+fn read_chunks(segment:Segment, chunking:Chunking, seq:&mut usize, sender:&SyncSender<RawChunk>) -> Result<bool> {
+    let Chunking { chunk_size, delimiter, .. } = chunking;
+    let overlap = chunking.ngrams.overlap();
+    let Segment { file: source, id: file, offset: mut file_offset, mtime, mut prev_tail } = segment;
+    let mut read_buff: Vec<u8> = vec![0;chunk_size + overlap]; // source reading chunk buffer, with look ahead for overlap
+    let mut filled :usize = 0; // valid bytes in read_buff, carried over to the next chunk
+    let mut eof = false;
 
+    loop {
         // fill buffer of chunk size
-        while remains>0 {
-            nread = source.read(&mut read_buff[read_count..])?;
-            if nread==0 { break };
-            remains -= nread;
-            read_count += nread;
+        while filled < read_buff.len() && !eof {
+            let nread = source.read(&mut read_buff[filled..])?;
+            if nread==0 { eof = true } else { filled += nread };
         };
+        if filled==0 { break };
 
-        // generate triple-bytes hashes from chunk
-        // hashes are hold in memory
-        let iter = read_buff[0..read_count].windows(3);
-        log::debug!("nread={}",nread);
-        iter.for_each(|s| {
-            bits[hash_3_to_2(s[0], s[1], s[2]) as usize]=true;
-            //bits[(s[0] as usize*s[1] as usize*s[2] as usize) % 65536]=true
-        });
-
-        // compress read_buff and write it to target
-        let compress_count = match compress_into(&read_buff[0..read_count as usize],&mut compressed_buffer) {
-            Ok(s) => { s }
-            Err(e) => { log::error!("an error at file:{} line:{} ,msg:{}",file!(),line!(), e); process::exit(1); }
-        };
-        target.write_all(&compressed_buffer[0..compress_count])?;
-    
-        
-        // add an index block
-        let mut ielm = Index{offset:compress_offset, compress_size:compress_count as u32, original_size:(read_count as u32),hash:[0u64;8192/8]};
-        log::debug!("offset={}, compress size={}",ielm.offset,ielm.compress_size);
-        fill_index(&mut ielm, &bits);
-        indexies.indexies.push(ielm);
-        indexies.n += 1;
+        let chunk_len = filled.min(chunk_size);
+        let last = eof && filled<=chunk_size;
+        if last && !chunking.partial_tail && delimiter.is_some_and(|d| !read_buff[0..filled].contains(&d)) {
+            log::info!("{} bytes of an unterminated record are left for the next append", filled);
+            break;
+        }
+        let read_count = chunk_end(&read_buff[0..chunk_len], delimiter, last && chunking.partial_tail);
+        let hard_cut = read_count<filled && delimiter.is_none_or(|d| read_buff[read_count-1]!=d);
+        log::debug!("filled={}, chunk={}, hard_cut={}",filled,read_count,hard_cut);
 
-        compress_offset += compress_count as u64;
+        // n-grams spanning a hard cut are hashed into both neighbouring chunks
+        let hash_end = if hard_cut { (read_count+overlap).min(filled) } else { read_count };
+        let chunk = RawChunk { seq: *seq, file, file_offset, mtime, data: read_buff[0..hash_end].to_vec(), lookahead: hash_end - read_count, prev_tail };
+        if sender.send(chunk).is_err() {
+            // the writer stopped on an error, which it reports
+            return Ok(false);
+        }
+        *seq += 1;
+        file_offset += read_count as u64;
 
+        prev_tail = if hard_cut { read_buff[read_count.saturating_sub(overlap)..read_count].to_vec() } else { Vec::new() };
+        read_buff.copy_within(read_count..filled, 0);
+        filled -= read_count;
+    };
+    Ok(true)
+}
 
-        if nread==0 { break };
+// index entry of the chunk data[0..data.len() - lookahead] of file at file_offset, file was modified at mtime
+// n-grams of prev_tail and the chunk, and of the chunk and its lookahead are hashed too
+// returns it with whether the chunk starts with lines continuing a record of the chunk before
+pub(crate) fn index_chunk(data:&[u8], lookahead:usize, mut prev_tail:Vec<u8>, file:u32, file_offset:u64, mtime:i64, chunking:Chunking) -> (Index, bool) {
+    let read_count = data.len() - lookahead;
+    let ngrams = chunking.ngrams;
+    let mut ielm = if chunking.fold {
+        Index::new_folded(ngrams, 0, 0, read_count as u32)
+    } else {
+        Index::new(ngrams, 0, 0, read_count as u32)
     };
+    ielm.file = file;
+    ielm.file_offset = file_offset;
+    ielm.original_checksum = checksum(&data[0..read_count]);
+    ielm.fill(ngrams, data);
+    let eol = chunking.delimiter.unwrap_or(b'\n');
+    ielm.lines = memchr_iter(eol, &data[0..read_count]).count() as u32;
+    ielm.cut = data[0..read_count].last().is_some_and(|b| *b != eol);
+    let mut continued = false;
+    if let Some(format) = chunking.time_format {
+        if let Some((min, max)) = time_range(&data[0..read_count], eol, format, latest_of(mtime)) {
+            ielm.min_time = min;
+            ielm.max_time = max;
+        }
+        continued = continues_record(&data[0..read_count], eol, format);
+    }
+    if !prev_tail.is_empty() {
+        prev_tail.extend_from_slice(&data[0..ngrams.overlap().min(read_count)]);
+        ielm.fill(ngrams, &prev_tail);
+    }
+    (ielm, continued)
+}
+
+// generate triple-bytes hashes from chunk and compress it
+// a chunk which does not shrink, e.g. random or already compressed data, is stored raw
+fn pack_chunk(chunk:RawChunk, compressed_buffer:&mut [u8], chunking:Chunking) -> Result<PackedChunk> {
+    let read_count = chunk.data.len() - chunk.lookahead;
+    let compress_count = compress_into(&chunk.data[0..read_count], compressed_buffer)
+        .map_err(|e| std::io::Error::other(format!("compression failed: {}", e)))?;
+    let (mut ielm, continued) = index_chunk(&chunk.data, chunk.lookahead, chunk.prev_tail, chunk.file, chunk.file_offset, chunk.mtime, chunking);
+    let stored = if compress_count < read_count { &compressed_buffer[0..compress_count] } else { &chunk.data[0..read_count] };
+    ielm.raw = compress_count >= read_count;
+    ielm.compress_size = stored.len() as u32;
+    ielm.compressed_checksum = checksum(stored);
+    Ok(PackedChunk { seq: chunk.seq, index: ielm, compressed: stored.to_vec(), continued })
+}
+
+// frame written in front of the compressed bytes of a chunk
+pub(crate) fn frame_of(ielm:&Index, entry:&FileEntry) -> ChunkFrame {
+    ChunkFrame {
+        compress_size: ielm.compress_size,
+        original_size: ielm.original_size,
+        file: ielm.file,
+        file_offset: ielm.file_offset,
+        compressed_checksum: ielm.compressed_checksum,
+        original_checksum: ielm.original_checksum,
+        mtime: entry.mtime,
+        name: entry.name.clone(),
+    }
+}
+
+// add a chunk after the last one, its line numbers and time range continue the chunk before of the same file
+pub(crate) fn push_chunk(indexies:&mut ListofIndex, mut ielm:Index, continued:bool) {
+    let before = indexies.indexies.last().filter(|before| before.file==ielm.file);
+    ielm.first_line = before.map_or(1, |before| before.first_line + before.lines as u64);
+    // leading lines without timestamp belong to the last record of the chunk before
+    if let Some(before) = before.filter(|before| continued && before.min_time<=before.max_time) {
+        ielm.min_time = ielm.min_time.min(before.max_time);
+        ielm.max_time = ielm.max_time.max(before.max_time);
+    }
+    indexies.indexies.push(ielm);
+    indexies.n += 1;
+}
+
+// cut segments into chunks, compress them and append them to target at compress_offset
+// a reader thread cuts chunks, threads workers hash and compress them and this thread writes them in order
+// returns the compressed offset after the last chunk
+fn write_chunks(segments:Vec<Segment>, target:&mut fs::File, indexies:&mut ListofIndex, mut compress_offset:u64, chunking:Chunking) -> Result<u64> {
+    let chunk_size = chunking.chunk_size;
+    let threads = chunking.threads.max(1);
+    let first = indexies.n as usize;
+
+    thread::scope(|scope| -> Result<()> {
+        // bounded queues keep at most a few chunks per worker in memory
+        let (raw_sender, raw_receiver) = sync_channel::<RawChunk>(threads);
+        let (packed_sender, packed_receiver) = sync_channel::<Result<PackedChunk>>(threads);
+        // the last worker to stop drops the queue, so the reader never blocks on a stopped pipeline
+        let raw_receiver = Arc::new(Mutex::new(raw_receiver));
+
+        let reader = scope.spawn(move || -> Result<()> {
+            let mut seq = 0;
+            for segment in segments {
+                if !read_chunks(segment, chunking, &mut seq, &raw_sender)? {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        for _ in 0..threads {
+            let packed_sender = packed_sender.clone();
+            let raw_receiver = Arc::clone(&raw_receiver);
+            scope.spawn(move || {
+                let mut compressed_buffer:Vec<u8> = vec![0;get_maximum_output_size(chunk_size)];
+                loop {
+                    let chunk = match raw_receiver.lock().unwrap().recv() {
+                        Ok(chunk) => chunk,
+                        Err(_) => break,
+                    };
+                    if packed_sender.send(pack_chunk(chunk, &mut compressed_buffer, chunking)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(packed_sender);
+        drop(raw_receiver);
 
+        // workers finish out of order, chunks are written by sequence number
+        let mut pending: BTreeMap<usize, PackedChunk> = BTreeMap::new();
+        for packed in packed_receiver.iter() {
+            let packed = packed?;
+            pending.insert(packed.seq, packed);
+            while let Some(mut packed) = pending.remove(&(indexies.n as usize - first)) {
+                let frame = frame_of(&packed.index, &indexies.files[packed.index.file as usize]).to_bytes();
+                target.write_all(&frame)?;
+                target.write_all(&packed.compressed)?;
+                packed.index.offset = compress_offset + frame.len() as u64;
+                log::debug!("offset={}, compress size={}",packed.index.offset,packed.index.compress_size);
+                compress_offset += (frame.len() + packed.compressed.len()) as u64;
+                push_chunk(indexies, packed.index, packed.continued);
+            }
+        }
+        reader.join().unwrap()
+    })?;
+    Ok(compress_offset)
+}
+
+// serialize and flush header and hashes to index file
+// when index is None, they are appended to target at compress_offset with a footer
+pub(crate) fn write_index(target:&mut fs::File, index:Option<&mut fs::File>, header:Header, indexies:&ListofIndex, compress_offset:u64) -> Result<()> {
     log::debug!("indexies: {:?}",indexies);
-    // serialize and flush hashes to index file
-    //let mut serializer = AllocSerializer::<0>::default();
-    //serializer.serialize_value(&indexies).unwrap();
-    //let bytes = serializer.into_serializer().into_inner();
-    let bytes = rkyv::to_bytes::<_, 256>(&indexies).unwrap();
+    let bytes = rkyv::to_bytes::<_, 256>(indexies)
+        .map_err(|e| std::io::Error::other(format!("cannot serialize the index: {:?}", e)))?;
     log::info!("bytes len: {:?}", bytes.len());
     log::debug!("bytes: {:x?}", bytes);
-    let archived = unsafe { rkyv::archived_root::<ListofIndex>(&bytes[..]) };
-    
-    //use rkyv::Deserialize;
-    //let deserialized: ListofIndex = archived.deserialize(&mut rkyv::Infallible).unwrap();
-    //assert_eq!(deserialized,indexies);
-    index.write_all(&bytes)
-}
\ No newline at end of file
+    match index {
+        Some(index) => {
+            index.write_all(&header.to_bytes())?;
+            index.write_all(&bytes)?;
+        },
+        None => {
+            // keep the embedded index 16 bytes aligned for rkyv
+            let padding = (16 - compress_offset % 16) % 16;
+            target.write_all(&vec![0u8; padding as usize])?;
+            let footer = Footer { index_offset: compress_offset + padding, index_len: (HEADER_SIZE + bytes.len()) as u64 };
+            target.write_all(&header.to_bytes())?;
+            target.write_all(&bytes)?;
+            target.write_all(&footer.to_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// bytes of file archived in indexies
+pub(crate) fn archived_size(indexies:&ListofIndex, file:u32) -> u64 {
+    indexies.indexies.iter().filter(|ielm| ielm.file==file).map(|ielm| ielm.original_size as u64).sum()
+}
+
+// create index
+// every source is cut into its own chunks and recorded in the manifest, in the given order
+// when index is None, the index and a footer are appended to target as a single-file archive
+// chunk size, delimiter, case folded bitmaps and the timestamp format of lines are given by header
+pub fn create_files(sources:&mut [Source], target:&mut fs::File,  index:Option<&mut fs::File>, header:Header, threads:usize) -> Result<()> {
+    let files = sources.iter().map(|source| source.entry()).collect::<Result<Vec<FileEntry>>>()?;
+    let segments: Vec<Segment> = sources.iter_mut().zip(&files).enumerate()
+        .map(|(id, (source, entry))| Segment { file: &mut source.file, id: id as u32, offset: 0, mtime: entry.mtime, prev_tail: Vec::new() })
+        .collect();
+    create_segments(files, segments, target, index, header, threads).map(|_| ())
+}
+
+// create an archive of segments, the files of the manifest, into the empty target
+// returns the number of chunks
+pub(crate) fn create_segments(files:Vec<FileEntry>, segments:Vec<Segment>, target:&mut fs::File, index:Option<&mut fs::File>, header:Header, threads:usize) -> Result<usize> {
+    let mut indexies :ListofIndex = ListofIndex { n: 0, files, indexies: Vec::new() };
+    let chunking = Chunking::new(header, threads, true);
+    // the archive starts with the header, so its chunks can be indexed again without the index
+    target.write_all(&header.to_bytes())?;
+    let compress_offset = write_chunks(segments, target, &mut indexies, HEADER_SIZE as u64, chunking)?;
+    for id in 0..indexies.files.len() {
+        indexies.files[id].size = archived_size(&indexies, id as u32);
+    }
+
+    write_index(target, index, header, &indexies, compress_offset)?;
+    Ok(indexies.indexies.len())
+}
+
+// new bytes of a source checked against the archive, to be written after its chunks
+struct Append {
+    header: Header,
+    indexies: ListofIndex,
+    id: u32,               // file of the manifest source continues
+    position: u64,         // source bytes already archived, the position new data starts from
+    prev_tail: Vec<u8>,    // end of the last chunk of source when it was cut inside a record
+    compress_offset: u64,  // end of the last chunk of the archive
+}
+
+// whether file has a complete record between position and len, append leaves a last record without delimiter
+fn has_record(file:&fs::File, position:u64, len:u64, delimiter:Option<u8>) -> Result<bool> {
+    let Some(delimiter) = delimiter else { return Ok(len > position) };
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut pos = position;
+    while pos < len {
+        let n = buf.len().min((len - pos) as usize);
+        file.read_exact_at(&mut buf[0..n], pos)?;
+        if buf[0..n].contains(&delimiter) {
+            return Ok(true);
+        }
+        pos += n as u64;
+    }
+    Ok(false)
+}
+
+// check that source still starts with the bytes archived in the archive of index_file
+// source continues the last file of the manifest, with new_segment it is a new file archived from its start
+// returns the bytes of source archived so far, and what to append when source has a new complete record or is a new file
+fn plan_append(source:&Source, archive:&fs::File, index_file:&IndexFile, new_segment:bool, embedded:bool) -> Result<(u64, Option<Append>)> {
+    let header = index_file.header;
+    let list = index_file.list();
+    let new_file = new_segment || list.files.is_empty();
+    let position = if new_file { 0 } else { list.files[list.files.len() - 1].size };
+
+    // the source must still start with the archived bytes, compare the last archived chunk of this source
+    let source_len = source.file.metadata()?.len();
+    let mut prev_tail = Vec::new();
+    match list.indexies.last() {
+        Some(last) if position > 0 => {
+            let mismatch = || Error::SourceMismatch(format!("{} does not continue the archive, it was truncated or replaced", source.name));
+            if source_len < position {
+                return Err(mismatch());
+            }
+            let mut compressed = vec![0u8; last.compress_size as usize];
+            archive.read_exact_at(&mut compressed, last.offset)?;
+            let mut chunk = vec![0u8; last.original_size as usize];
+            expand_chunk(&compressed, last.raw, &mut chunk)
+                .map_err(|message| Error::Decompress { chunk: list.indexies.len() - 1, offset: last.offset, message })?;
+            let mut original = vec![0u8; chunk.len()];
+            source.file.read_exact_at(&mut original, last.file_offset)?;
+            if original != chunk {
+                return Err(mismatch());
+            }
+            if header.delimiter.is_none_or(|d| chunk.last() != Some(&d)) {
+                prev_tail = chunk[chunk.len().saturating_sub(header.ngrams().overlap())..].to_vec();
+            }
+        },
+        _ => {},
+    }
+    log::info!("{} chunks archived, {} new bytes in {}", list.n, source_len - position, source.name);
+    // the archive is not written again without a new chunk or a new segment
+    if !new_segment && !has_record(&source.file, position, source_len, header.delimiter)? {
+        return Ok((position, None));
+    }
+
+    let mut indexies: ListofIndex = list.deserialize(&mut rkyv::Infallible).unwrap_or_else(|e| match e {});
+    // new chunks of a separate index archive replace whatever follows its last chunk,
+    // a single-file archive only grows, they go after its footer and the old index is left unread
+    let compress_offset = match embedded {
+        true => archive.metadata()?.len(),
+        false => indexies.indexies.last().map_or(HEADER_SIZE as u64, |ielm| ielm.offset + ielm.compress_size as u64),
+    };
+    if new_file {
+        indexies.files.push(source.entry()?);
+    }
+    let id = indexies.files.len() as u32 - 1;
+    Ok((position, Some(Append { header, indexies, id, position, prev_tail, compress_offset })))
+}
+
+// write the new chunks of source after the chunks of target and then the extended index,
+// to index when given, otherwise embedded into target
+// returns the bytes of source archived so far
+fn write_append(append:Append, source:&mut Source, target:&mut fs::File, index:Option<&mut fs::File>, threads:usize) -> Result<u64> {
+    let Append { header, mut indexies, id, position, prev_tail, compress_offset } = append;
+    source.file.seek(SeekFrom::Start(position))?;
+    target.set_len(compress_offset)?;
+    target.seek(SeekFrom::Start(compress_offset))?;
+    let chunking = Chunking::new(header, threads, false);
+    // frames of the new chunks record the modification time they are indexed with
+    let mtime = source.file.metadata()?.mtime();
+    indexies.files[id as usize].mtime = mtime;
+    let segment = Segment { file: &mut source.file, id, offset: position, mtime, prev_tail };
+    let compress_offset = write_chunks(vec![segment], target, &mut indexies, compress_offset, chunking)?;
+    indexies.files[id as usize].size = archived_size(&indexies, id);
+    write_index(target, index, header, &indexies, compress_offset)?;
+    Ok(indexies.files[id as usize].size)
+}
+
+// path of a file written next to path and renamed over it when complete
+fn new_path(path:&Path) -> PathBuf {
+    let mut new_path = path.to_path_buf().into_os_string();
+    new_path.push(".new");
+    PathBuf::from(new_path)
+}
+
+// archive the bytes source got since it was last archived into the archive at target_path
+// chunk size, delimiter, case folding and timestamp format are taken from the existing index,
+// which is embedded in the archive or at index_path
+// existing chunks are kept as they are, new chunks are written after them and the index is written again,
+// nothing is written when source has no new complete record
+// searches may have the index mapped, so its bytes are never written again: a single-file archive grows by
+// the new chunks, a new index and a new footer, a separate index is written next to the old one and replaces it
+// source continues the last file of the manifest, a last record without delimiter is left for the next append
+// with new_segment, source is a new file, e.g. after a log rotation, and is archived from its start
+// returns the bytes of source archived so far
+pub fn append_archive(source:&mut Source, target_path:&Path, index_path:&Path, threads:usize, new_segment:bool) -> Result<u64> {
+    let archive = fs::File::open(target_path)?;
+    match IndexFile::open_embedded(&archive)? {
+        Some(index_file) => {
+            let (archived, append) = plan_append(source, &archive, &index_file, new_segment, true)?;
+            let Some(append) = append else { return Ok(archived) };
+            let mut target = fs::OpenOptions::new().write(true).open(target_path)?;
+            write_append(append, source, &mut target, None, threads)
+        },
+        None => {
+            let index_file = IndexFile::open(&fs::File::open(index_path)?)?;
+            let (archived, append) = plan_append(source, &archive, &index_file, new_segment, false)?;
+            let Some(append) = append else { return Ok(archived) };
+            // readers find chunks through the index, bytes after its last chunk are not read
+            let mut target = fs::OpenOptions::new().write(true).open(target_path)?;
+            let mut index = fs::OpenOptions::new().write(true).create(true).truncate(true).open(new_path(index_path))?;
+            let archived = write_append(append, source, &mut target, Some(&mut index), threads)?;
+            fs::rename(new_path(index_path), index_path)?;
+            Ok(archived)
+        },
+    }
+}
+
+// update a copy of the single-file archive at path, which replaces it when complete
+// searches may have its embedded index mapped, so it is not written in place
+fn update_copy<T>(path:&Path, update:impl FnOnce(&mut fs::File) -> Result<T>) -> Result<T> {
+    fs::copy(path, new_path(path))?;
+    let mut target = fs::OpenOptions::new().read(true).write(true).open(new_path(path))?;
+    let result = update(&mut target)?;
+    fs::rename(new_path(path), path)?;
+    Ok(result)
+}
+
+// archive without chunks, to be filled by append
+pub fn create_empty(target:&mut fs::File, index:Option<&mut fs::File>, header:Header) -> Result<()> {
+    target.write_all(&header.to_bytes())?;
+    write_index(target, index, header, &ListofIndex { n: 0, files: Vec::new(), indexies: Vec::new() }, HEADER_SIZE as u64)
+}
+
+// settings of new archives, used to create, append to and watch archives
+pub struct ArchiveWriter {
+    header: Header,
+    threads: usize,
+}
+
+impl Default for ArchiveWriter {
+    fn default() -> ArchiveWriter {
+        ArchiveWriter::new()
+    }
+}
+
+impl ArchiveWriter {
+    // 4MB chunks aligned to lines, hashed and compressed on all CPUs
+    pub fn new() -> ArchiveWriter {
+        ArchiveWriter { header: Header::new(4 * 1024 * 1024), threads: thread::available_parallelism().map_or(1, |n| n.get()) }
+    }
+
+    // chunk size has to be a power of two
+    pub fn chunk_size(mut self, chunk_size:usize) -> ArchiveWriter {
+        self.header.chunk_size = u32::try_from(chunk_size).unwrap_or(0);
+        self
+    }
+
+    // record delimiter chunks are aligned to, None cuts chunks at the exact chunk size
+    pub fn delimiter(mut self, delimiter:Option<u8>) -> ArchiveWriter {
+        self.header.delimiter = delimiter;
+        self
+    }
+
+    // also index ASCII case folded trigrams for case-insensitive search
+    pub fn fold(mut self, fold:bool) -> ArchiveWriter {
+        self.header.folded = fold;
+        self
+    }
+
+    // record the time range of the lines of every chunk
+    pub fn timestamps(mut self, time_format:Option<TimeFormat>) -> ArchiveWriter {
+        self.header.time_format = time_format;
+        self
+    }
+
+    // n-grams of len bytes hashed into bitmaps of 2^bits bits, 3 and 16 by default
+    // longer n-grams prune long queries better, queries shorter than len are pruned by bytes and bigrams only
+    pub fn ngrams(mut self, len:usize, bits:u32) -> ArchiveWriter {
+        self.header.ngram = u8::try_from(len).unwrap_or(0);
+        self.header.hash_bits = u8::try_from(bits).unwrap_or(0);
+        self.header.hash_fn = if (len, bits)==(3, 16) { HASH_3_TO_2 } else { HASH_MULTIPLY };
+        self
+    }
+
+    pub fn threads(mut self, threads:usize) -> ArchiveWriter {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn settings(&self) -> Header {
+        self.header
+    }
+
+    // settings of the archive at target_path, to reindex it with some of them changed
+    pub fn of_archive(target_path:&Path) -> Result<ArchiveWriter> {
+        let mut head = [0u8; HEADER_SIZE];
+        fs::File::open(target_path)?.read_exact_at(&mut head, 0)?;
+        Ok(ArchiveWriter { header: Header::from_bytes_upgrade(&head)?, ..ArchiveWriter::new() })
+    }
+
+    fn header(&self) -> Result<Header> {
+        if !self.header.chunk_size.is_power_of_two() {
+            return Err(Error::InvalidInput("chunk size has to be a power of two below 4GB".to_string()));
+        }
+        self.header.check_ngrams().map_err(Error::InvalidInput)?;
+        Ok(self.header)
+    }
+
+    // archive sources into target_path, the index is written to index_path or embedded into target_path
+    pub fn create(&self, sources:&mut [Source], target_path:&Path, index_path:Option<&Path>) -> Result<()> {
+        let header = self.header()?;
+        let mut target = fs::OpenOptions::new().write(true).create(true).truncate(true).open(target_path)?;
+        match index_path {
+            Some(index_path) => {
+                let mut index = fs::OpenOptions::new().write(true).create(true).truncate(true).open(index_path)?;
+                create_files(sources, &mut target, Some(&mut index), header, self.threads)
+            },
+            None => create_files(sources, &mut target, None, header, self.threads),
+        }
+    }
+
+    // archive without chunks, to be filled by append or watch
+    pub fn create_empty(&self, target_path:&Path, index_path:Option<&Path>) -> Result<()> {
+        let header = self.header()?;
+        let mut target = fs::File::create(target_path)?;
+        match index_path {
+            Some(index_path) => create_empty(&mut target, Some(&mut fs::File::create(index_path)?), header),
+            None => create_empty(&mut target, None, header),
+        }
+    }
+
+    // archive the bytes source got since it was last archived into target_path, see append_archive
+    // the settings of the existing archive are kept, its index is embedded or at index_path
+    // returns the bytes of source archived so far
+    pub fn append(&self, source:&mut Source, target_path:&Path, index_path:&Path, new_segment:bool) -> Result<u64> {
+        append_archive(source, target_path, index_path, self.threads, new_segment)
+    }
+
+    // index the chunks of target_path again from their frames, see repair_archive
+    // the index is written to index_path or embedded into a copy of target_path replacing it, the settings of the archive are kept
+    pub fn repair(&self, target_path:&Path, index_path:Option<&Path>) -> Result<Repaired> {
+        match index_path {
+            Some(index_path) => {
+                let mut target = fs::OpenOptions::new().read(true).write(true).open(target_path)?;
+                let mut index = fs::OpenOptions::new().write(true).create(true).truncate(true).open(new_path(index_path))?;
+                let repaired = repair_archive(&mut target, Some(&mut index))?;
+                fs::rename(new_path(index_path), index_path)?;
+                Ok(repaired)
+            },
+            None => update_copy(target_path, |target| repair_archive(target, None)),
+        }
+    }
+
+    // index the archive at target_path again with these settings, without its original files
+    // its chunks are kept and only the index is written again, see reindex_archive,
+    // or with rechunk they are cut anew with the chunk size and delimiter of these settings and compressed again
+    // into a new archive which replaces the old one, see rechunk_archive
+    // the index is written to index_path or embedded into a copy of target_path replacing it
+    pub fn reindex(&self, target_path:&Path, index_path:Option<&Path>, rechunk:bool) -> Result<Repaired> {
+        let header = self.header()?;
+        let mut index = match index_path {
+            Some(index_path) => Some(fs::OpenOptions::new().write(true).create(true).truncate(true).open(new_path(index_path))?),
+            None => None,
+        };
+        let repaired = if rechunk {
+            let archive = fs::File::open(target_path)?;
+            let mut target = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(new_path(target_path))?;
+            let repaired = rechunk_archive(&archive, &mut target, index.as_mut(), header, self.threads)?;
+            fs::rename(new_path(target_path), target_path)?;
+            repaired
+        } else if index.is_some() {
+            let mut target = fs::OpenOptions::new().read(true).write(true).open(target_path)?;
+            reindex_archive(&mut target, index.as_mut(), header)?
+        } else {
+            update_copy(target_path, |target| reindex_archive(target, None, header))?
+        };
+        if let Some(index_path) = index_path {
+            fs::rename(new_path(index_path), index_path)?;
+        }
+        Ok(repaired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{source_of, temp_file};
+    use crate::index::IndexFile;
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::FileExt;
+
+    // create an archive of text and return (bitmap, original bytes) of every chunk
+    fn chunks_of(name:&str, text:&[u8], chunk_size:usize, delimiter:Option<u8>) -> Vec<(Vec<u64>, Vec<u8>)> {
+        let mut target = temp_file(&format!("{}-target", name));
+        let mut index = temp_file(&format!("{}-index", name));
+        create_files(&mut [source_of(name, text)], &mut target, Some(&mut index), Header { delimiter, ..Header::new(chunk_size) }, 1).unwrap();
+
+        let index_file = IndexFile::open(&index).unwrap();
+        index_file.list().indexies.iter().map(|ielm| {
+            let mut compressed = vec![0u8; ielm.compress_size as usize];
+            target.read_exact_at(&mut compressed, ielm.offset).unwrap();
+            let mut original = vec![0u8; ielm.original_size as usize];
+            expand_chunk(&compressed, ielm.raw, &mut original).unwrap();
+            (ielm.hash.to_vec(), original)
+        }).collect()
+    }
+
+    fn covers(hash:&[u64], query:&[u8]) -> bool {
+        Ngrams::default().contains(hash, query)
+    }
+
+    fn sample_text() -> Vec<u8> {
+        let mut text = Vec::new();
+        for i in 0..200 {
+            text.extend_from_slice(format!("{} host{} level={} value={}\n", i, i % 7, ["ERROR","info","warn"][i % 3], i * 7919 % 1000).as_bytes());
+        }
+        text
+    }
+
+    #[test]
+    fn test_chunk_end() {
+        assert_eq!(chunk_end(b"ab\ncd\nef", Some(b'\n'), false), 6);
+        assert_eq!(chunk_end(b"ab\ncd\nef", Some(b'\n'), true), 8);
+        assert_eq!(chunk_end(b"abcdef", Some(b'\n'), false), 6);
+        assert_eq!(chunk_end(b"ab\ncd\nef", None, false), 8);
+    }
+
+    #[test]
+    fn test_aligned_chunks_keep_lines() {
+        let text = sample_text();
+        let chunks = chunks_of("aligned", &text, 256, Some(b'\n'));
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.iter().flat_map(|(_, c)| c.clone()).collect::<Vec<u8>>(), text);
+        for (_, c) in chunks.iter().take(chunks.len() - 1) {
+            assert_eq!(c.last(), Some(&b'\n'));
+        }
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let text = sample_text();
+        for delimiter in [Some(b'\n'), None] {
+            let chunks = chunks_of(&format!("nofn-{}", delimiter.is_some()), &text, 128, delimiter);
+            // original byte range of every chunk
+            let mut ranges = Vec::new();
+            let mut start = 0;
+            for (_, original) in chunks.iter() {
+                ranges.push(start..start + original.len());
+                start += original.len();
+            }
+
+            let mut line_start = 0;
+            for line in text.split_inclusive(|x| *x==b'\n') {
+                // every substring up to 8 bytes and the whole line
+                let queries = (0..line.len()-2)
+                    .flat_map(|s| (s+3..=line.len().min(s+8)).map(move |e| (s, e)))
+                    .chain(std::iter::once((0, line.len())));
+                for (s, e) in queries {
+                    let (s, e) = (line_start + s, line_start + e);
+                    let query = &text[s..e];
+                    let holders: Vec<usize> = ranges.iter().enumerate()
+                        .filter(|(_, r)| r.start < e && s < r.end)
+                        .map(|(i, _)| i).collect();
+                    // with aligned chunks, the whole line is inside one chunk
+                    if delimiter.is_some() {
+                        assert_eq!(holders.len(), 1, "line split {:?}", String::from_utf8_lossy(query));
+                    }
+                    // a query inside one chunk, or spanning a cut within 2 bytes, must pass the prefilter
+                    if holders.len()==1 || (holders.len()==2 && query.len()==3) {
+                        for i in holders {
+                            assert!(covers(&chunks[i].0, query), "missed {:?}", String::from_utf8_lossy(query));
+                        }
+                    }
+                }
+                line_start += line.len();
+            }
+        }
+    }
+
+    #[test]
+    fn test_threads_same_output() {
+        let text = sample_text().repeat(20);
+        let archive = |threads| {
+            let mut target = temp_file(&format!("threads-{}-target", threads));
+            // frames record the modification time of the source, source_of pins it
+            create_files(&mut [source_of("threads", &text)], &mut target, None, Header { delimiter: None, folded: true, ..Header::new(256) }, threads).unwrap();
+            let mut bytes = Vec::new();
+            target.seek(SeekFrom::Start(0)).unwrap();
+            target.read_to_end(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(archive(1), archive(4));
+    }
+
+    // original bytes of every chunk of a single-file archive
+    fn archive_chunks(target:&fs::File) -> Vec<Vec<u8>> {
+        let index_file = IndexFile::open_embedded(target).unwrap().unwrap();
+        index_file.list().indexies.iter().map(|ielm| {
+            let mut compressed = vec![0u8; ielm.compress_size as usize];
+            target.read_exact_at(&mut compressed, ielm.offset).unwrap();
+            let mut original = vec![0u8; ielm.original_size as usize];
+            expand_chunk(&compressed, ielm.raw, &mut original).unwrap();
+            original
+        }).collect()
+    }
+
+    // every byte of a file
+    fn file_bytes(file:&fs::File) -> Vec<u8> {
+        let mut bytes = vec![0u8; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut bytes, 0).unwrap();
+        bytes
+    }
+
+    // append to a single-file archive as append_archive does
+    fn append(source:&mut Source, target:&mut fs::File, threads:usize) -> Result<()> {
+        let index_file = IndexFile::open_embedded(target)?.unwrap();
+        match plan_append(source, target, &index_file, false, true)? {
+            (_, Some(append)) => write_append(append, source, target, None, threads).map(|_| ()),
+            (_, None) => Ok(()),
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let text = sample_text();
+        let mut sources = [source_of("append", &text[0..1000])];
+        let mut target = temp_file("append-target");
+        create_files(&mut sources, &mut target, None, Header { folded: true, ..Header::new(256) }, 2).unwrap();
+        let source = &mut sources[0];
+        let created = archive_chunks(&target);
+
+        // the unterminated last line is left for the next append
+        source.file.write_all(&text[1000..2000]).unwrap();
+        append(source, &mut target, 2).unwrap();
+        let appended = archive_chunks(&target);
+        assert_eq!(appended[0..created.len()], created[..]);
+        let archived = appended.concat();
+        assert!(archived.len() < 2000 && archived.ends_with(b"\n"));
+        assert_eq!(archived, text[0..archived.len()]);
+
+        // the archive only grows, a search with the old index mapped keeps reading the bytes it mapped
+        let before = file_bytes(&target);
+        source.file.write_all(&text[2000..]).unwrap();
+        append(source, &mut target, 2).unwrap();
+        let after = file_bytes(&target);
+        assert!(after.len() > before.len());
+        assert_eq!(after[0..before.len()], before[..]);
+        assert_eq!(archive_chunks(&target).concat(), text);
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        assert!(index_file.header.folded);
+        // line numbers continue over appends
+        for ielm in index_file.list().indexies.iter() {
+            let lines_before = text[0..ielm.file_offset as usize].iter().filter(|x| **x==b'\n').count();
+            assert_eq!(ielm.first_line, lines_before as u64 + 1);
+        }
+        drop(index_file);
+
+        // the indexes left between the chunks are not taken for damaged chunks
+        let repaired = crate::repair::repair_archive(&mut target, None).unwrap();
+        assert_eq!(repaired.skipped, 0);
+        assert_eq!(archive_chunks(&target).concat(), text);
+    }
+
+    #[test]
+    fn test_repair_on_copy() {
+        let text = sample_text();
+        let path = std::env::temp_dir().join(format!("mmsearch-test-repair-copy-{}.mms", std::process::id()));
+        let mut target = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        create_files(&mut [source_of("repair-copy", &text)], &mut target, None, Header::new(256), 1).unwrap();
+        // a search having the embedded index mapped keeps reading the old file
+        let searched = fs::File::open(&path).unwrap();
+        let index_file = IndexFile::open_embedded(&searched).unwrap().unwrap();
+        let before = file_bytes(&searched);
+        ArchiveWriter::new().repair(&path, None).unwrap();
+        assert_ne!(fs::metadata(&path).unwrap().ino(), searched.metadata().unwrap().ino());
+        ArchiveWriter::new().ngrams(4, 12).reindex(&path, None, false).unwrap();
+        assert_eq!(file_bytes(&searched), before);
+        let reindexed = fs::File::open(&path).unwrap();
+        let reindexed_index = IndexFile::open_embedded(&reindexed).unwrap().unwrap();
+        assert_eq!((reindexed_index.header.ngram, reindexed_index.list().n), (4, index_file.list().n));
+        assert_eq!(archive_chunks(&reindexed).concat(), text);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append_replaced_source() {
+        let text = sample_text();
+        let mut sources = [source_of("replaced", &text)];
+        let mut target = temp_file("replaced-target");
+        create_files(&mut sources, &mut target, None, Header::new(256), 1).unwrap();
+        let source = &mut sources[0];
+
+        // the last archived line changed
+        source.file.write_all_at(b"X", text.len() as u64 - 2).unwrap();
+        assert!(matches!(append(source, &mut target, 1), Err(Error::SourceMismatch(_))));
+        // truncated
+        source.file.set_len(10).unwrap();
+        assert!(matches!(append(source, &mut target, 1), Err(Error::SourceMismatch(_))));
+    }
+
+    #[test]
+    fn test_manifest() {
+        let text = sample_text();
+        let mut sources = [source_of("manifest-a", &text[0..1001]), source_of("manifest-b", &text[1001..])];
+        let mut target = temp_file("manifest-target");
+        create_files(&mut sources, &mut target, None, Header::new(256), 2).unwrap();
+
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        let list = index_file.list();
+        assert_eq!(list.files.iter().map(|f| (f.name.as_str(), f.size)).collect::<Vec<_>>(),
+            vec![("manifest-a.log", 1001), ("manifest-b.log", text.len() as u64 - 1001)]);
+        // a chunk never spans two files, and the partial last line of a file is its own
+        let chunks = archive_chunks(&target);
+        for (ielm, chunk) in list.indexies.iter().zip(chunks.iter()) {
+            let start = if ielm.file==0 { 0 } else { 1001 } + ielm.file_offset as usize;
+            assert_eq!(&text[start..start + chunk.len()], &chunk[..]);
+        }
+        let first_b = list.indexies.iter().position(|ielm| ielm.file==1).unwrap();
+        assert_eq!(list.indexies[first_b].file_offset, 0);
+        // line numbers start again in every file
+        assert_eq!(list.indexies[first_b].first_line, 1);
+        assert_eq!(chunks[0..first_b].concat(), &text[0..1001]);
+    }
+
+    #[test]
+    fn test_overlap_on_hard_cut() {
+        // one long record cut at exact chunk size, trigrams across the cut are in both chunks
+        let text = b"abcdefghijklmnopqrstuvwxyz".repeat(3);
+        let chunks = chunks_of("overlap", &text, 16, Some(b'\n'));
+        assert_eq!(chunks[0].1.len(), 16);
+        let spanning = &text[14..17];
+        assert!(covers(&chunks[0].0, spanning));
+        assert!(covers(&chunks[1].0, spanning));
+    }
+}
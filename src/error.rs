@@ -0,0 +1,75 @@
+use std::fmt;
+
+// errors of the library, nothing in it exits the process
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    // the index or the archive framing is truncated, inconsistent or not an mmsearch file
+    CorruptIndex(String),
+    // a chunk does not decompress to the size the index records
+    Decompress { chunk: usize, offset: u64, message: String },
+    // the bytes of a chunk do not match the checksum its index entry records
+    Checksum { chunk: usize, offset: u64, message: String },
+    // the index was written by another format version, the archive has to be recreated or reindexed
+    Version { found: u32, expected: u32 },
+    // a glob, regex or boolean expression which cannot be searched
+    Query(String),
+    // an option or argument which does not fit the archive, e.g. a line number past the end
+    InvalidInput(String),
+    // the source given to append does not continue the archive, it was truncated or replaced
+    SourceMismatch(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::CorruptIndex(msg) => write!(f, "corrupted index: {}", msg),
+            Error::Decompress { chunk, offset, message } => write!(f, "chunk {} at offset {}: {}", chunk, offset, message),
+            Error::Checksum { chunk, offset, message } => write!(f, "chunk {} at offset {}: {}", chunk, offset, message),
+            Error::Version { found, expected } => write!(f, "unsupported index format version {} (expected {}), recreate the archive or upgrade it with reindex", found, expected),
+            Error::Query(msg) => write!(f, "invalid query: {}", msg),
+            Error::InvalidInput(msg) => write!(f, "{}", msg),
+            Error::SourceMismatch(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e:std::io::Error) -> Error {
+        // errors of the library passed through Read and Seek come back as they were
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<nix::errno::Errno> for Error {
+    fn from(e:nix::errno::Errno) -> Error {
+        Error::Io(e.into())
+    }
+}
+
+// Read and Seek of ArchiveReader return io errors, which carry the error of the library
+impl From<Error> for std::io::Error {
+    fn from(e:Error) -> std::io::Error {
+        use std::io::ErrorKind;
+        match e {
+            Error::Io(e) => e,
+            Error::InvalidInput(_) | Error::Query(_) => std::io::Error::new(ErrorKind::InvalidInput, e),
+            _ => std::io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -1,32 +1,94 @@
-use std::process;
 use std::fs::File;
-use std::io::{Read, Write};
-use crate::index::ListofIndex;
-use lz4_flex::block::{decompress_into, get_maximum_output_size};
-use rkyv::Deserialize;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use crate::error::{Error, Result};
+use crate::index::{expand_chunk, IndexFile};
+use crate::reader::ArchiveReader;
+use crate::verify::Checksums;
+use memchr::memchr_iter;
 
-// create index
-pub fn expand_file(source:&mut File, target:&mut File,  index:&mut File,chunk_size:usize) -> std::io::Result<()> {
-    let mut read_buff: Vec<u8> = vec![0;chunk_size]; // source reading chunk buffer
-    let mut expand_buffer:Vec<u8> = vec![0;get_maximum_output_size(chunk_size)];
+// write the original bytes of archive to out, files of the manifest are concatenated
+// with checksums, every chunk is checked against its checksums
+pub fn expand_file(archive:&File, index_file:&IndexFile, checksums:bool, out:&mut impl Write) -> Result<u64> {
+    log::debug!("index len = {}", index_file.list().n);
+    // chunks are read once in order, so one of them is cached
+    let mut reader = ArchiveReader::new(archive.try_clone()?, index_file).with_cache(1).with_checksums(checksums);
+    let size = std::io::copy(&mut reader, out)?;
+    log::info!("{} bytes expanded", size);
+    Ok(size)
+}
 
-    // read out index and evaluate as ListofIndex
-    let mut index_buff_u8: Vec<u8> = Vec::new();
-    let read_to_end_ret =index.read_to_end(&mut index_buff_u8);
-    log::info!("index: {:?}, read_to_end_ret: {:?}, len of index_buff_u8: {:?}", index, read_to_end_ret,index_buff_u8.len());
-    log::debug!("index_buff_u8: {:x?}",index_buff_u8);
-    let archived = unsafe { rkyv::archived_root::<ListofIndex>(&index_buff_u8[..]) };
-    let deserialized: ListofIndex = archived.deserialize(&mut rkyv::Infallible).unwrap();
-    log::debug!("deserialize len = {}", deserialized.n);
-    
-    for idx in deserialized.indexies {
-        log::info!("idx: {:?}",idx);
-        source.read_exact(&mut read_buff[0..idx.compress_size as usize])?;
-        match decompress_into(&read_buff[0..idx.compress_size as usize], &mut expand_buffer) {
-            Err(e) =>    { log::error!("an error at file:{} line:{} ,msg:{}",file!(),line!(), e); process::exit(1); },
-            _ => {}
-        };
-        target.write_all(&expand_buffer[0..(idx.original_size as usize)])?;
-    };
+// position after the n-th eol of chunk, or its end when it has fewer
+fn after_eols(chunk:&[u8], eol:u8, n:u64) -> usize {
+    if n==0 {
+        return 0;
+    }
+    memchr_iter(eol, chunk).nth(n as usize - 1).map_or(chunk.len(), |pos| pos + 1)
+}
+
+// write the lines from line - context to line + context of a file of the archive, numbered from 1
+// only chunks holding those lines are read and decompressed
+pub fn show_lines(archive:&File, index_file:&IndexFile, checksums:bool, file:u32, line:u64, context:u64, out:&mut impl Write) -> Result<()> {
+    let first = line.saturating_sub(context).max(1);
+    let last = line.saturating_add(context);
+    let eol = index_file.header.delimiter.unwrap_or(b'\n');
+    let mut compressed: Vec<u8> = Vec::new();
+    let mut chunk: Vec<u8> = Vec::new();
+    let mut found = false;
+
+    let archived = index_file.list();
+    for (id, idx) in archived.indexies.iter().enumerate().filter(|(_, idx)| idx.file==file) {
+        // the chunk holds its first line up to the line after its last eol
+        let chunk_first = idx.first_line;
+        if chunk_first > last || chunk_first + (idx.lines as u64) < first {
+            continue;
+        }
+        log::debug!("idx: offset={} first_line={} lines={}", idx.offset, idx.first_line, idx.lines);
+        compressed.resize(idx.compress_size as usize, 0);
+        chunk.resize(idx.original_size as usize, 0);
+        archive.read_exact_at(&mut compressed, idx.offset)?;
+        if checksums {
+            Checksums::of(idx).check_compressed(id, idx.offset, &compressed)?;
+        }
+        expand_chunk(&compressed, idx.raw, &mut chunk)
+            .map_err(|message| Error::Decompress { chunk: id, offset: idx.offset, message })?;
+        if checksums {
+            Checksums::of(idx).check_original(id, idx.offset, &chunk)?;
+        }
+        let start = after_eols(&chunk, eol, first.saturating_sub(chunk_first));
+        let end = after_eols(&chunk, eol, last + 1 - chunk_first);
+        found |= start < end && chunk_first <= line && line <= chunk_first + idx.lines as u64;
+        out.write_all(&chunk[start..end])?;
+    }
+    if !found {
+        return Err(Error::InvalidInput(format!("line {} is not in {}", line, archived.files.get(file as usize).map_or("the archive", |f| f.name.as_str()))));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{source_of, temp_file};
+    use crate::create_files::create_files;
+    use crate::header::{Header, FORMAT_VERSION};
+
+    #[test]
+    fn test_show_lines() {
+        let text: String = (1..=500).map(|i| format!("line {}\n", i)).collect();
+        let mut target = temp_file("show-target");
+        create_files(&mut [source_of("show", text.as_bytes())], &mut target, None, Header::new(64), 1).unwrap();
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        assert_eq!(index_file.header.version, FORMAT_VERSION);
+
+        let show = |line, context| {
+            let mut out = Vec::new();
+            show_lines(&target, &index_file, true, 0, line, context, &mut out).map(|_| String::from_utf8(out).unwrap())
+        };
+        let expected: String = (240..=260).map(|i| format!("line {}\n", i)).collect();
+        assert_eq!(show(250, 10).unwrap(), expected);
+        assert_eq!(show(2, 5).unwrap(), "line 1\nline 2\nline 3\nline 4\nline 5\nline 6\nline 7\n");
+        assert_eq!(show(500, 1).unwrap(), "line 499\nline 500\n");
+        assert!(show(501, 0).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::error::{Error, Result};
+use memchr::memmem;
+use crate::prefilter::{Prefilter, Case};
+
+// boolean query, e.g. error AND (disk OR nvme) AND NOT "debug"
+//
+//  or   := and ( OR and )*
+//  and  := not ( [AND] not )*      adjacent terms are AND-ed
+//  not  := NOT not | atom
+//  atom := ( or ) | word | "quoted string"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Term(Vec<u8>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+// where every term of the expression has to be found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    Line,
+    Chunk,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(Vec<u8>),
+    Quoted(Vec<u8>),
+    Open,
+    Close,
+}
+
+fn invalid(msg:String) -> Error {
+    Error::Query(msg)
+}
+
+fn tokenize(s:&str) -> Result<Vec<Token>> {
+    let bytes = s.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b' ' | b'\t' => { i += 1; },
+            b'(' => { tokens.push(Token::Open); i += 1; },
+            b')' => { tokens.push(Token::Close); i += 1; },
+            b'"' => {
+                let mut term = Vec::new();
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None => return Err(invalid("unterminated quoted string".to_string())),
+                        Some(b'"') => { i += 1; break; },
+                        Some(b'\\') if i+1 < bytes.len() => { term.push(bytes[i+1]); i += 2; },
+                        Some(x) => { term.push(*x); i += 1; },
+                    }
+                }
+                tokens.push(Token::Quoted(term));
+            },
+            _ => {
+                let start = i;
+                while i < bytes.len() && !b" \t()\"".contains(&bytes[i]) { i += 1; }
+                tokens.push(Token::Word(bytes[start..i].to_vec()));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn keyword(&self, k:&[u8]) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w==k)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut list = vec![self.and()?];
+        while self.keyword(b"OR") {
+            self.pos += 1;
+            list.push(self.and()?);
+        }
+        Ok(if list.len()==1 { list.pop().unwrap() } else { Expr::Or(list) })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut list = vec![self.not()?];
+        loop {
+            if self.keyword(b"AND") {
+                self.pos += 1;
+            } else if self.pos >= self.tokens.len() || self.keyword(b"OR") || self.tokens[self.pos]==Token::Close {
+                break;
+            }
+            list.push(self.not()?);
+        }
+        Ok(if list.len()==1 { list.pop().unwrap() } else { Expr::And(list) })
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.keyword(b"NOT") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => { self.pos += 1; Ok(expr) },
+                    _ => Err(invalid("missing )".to_string())),
+                }
+            },
+            Some(Token::Word(w)) if w==b"AND" || w==b"OR" || w==b"NOT" => Err(invalid(format!("unexpected {}", String::from_utf8_lossy(w)))),
+            Some(Token::Word(w)) | Some(Token::Quoted(w)) if !w.is_empty() => Ok(Expr::Term(w.clone())),
+            Some(Token::Quoted(_)) => Err(invalid("empty quoted string".to_string())),
+            Some(_) => Err(invalid("unexpected )".to_string())),
+            None => Err(invalid("unexpected end of query".to_string())),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(s:&str) -> Result<Expr> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0 };
+        let expr = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(invalid("unexpected )".to_string()));
+        }
+        Ok(expr)
+    }
+
+    // positive terms give required trigrams, negated parts cannot prune
+    pub fn prefilter(&self, case:Case) -> Prefilter {
+        match self {
+            Expr::Term(t) => Prefilter::from_literals_case(&[t], case),
+            Expr::And(list) => Prefilter::and(list.iter().map(|e| e.prefilter(case)).collect()),
+            Expr::Or(list) => Prefilter::or(list.iter().map(|e| e.prefilter(case)).collect()),
+            Expr::Not(_) => Prefilter::All,
+        }
+    }
+
+    // the same expression with ASCII lowercased terms, evaluated against lowercased text
+    pub fn to_ascii_lowercase(&self) -> Expr {
+        match self {
+            Expr::Term(t) => Expr::Term(t.to_ascii_lowercase()),
+            Expr::And(list) => Expr::And(list.iter().map(|e| e.to_ascii_lowercase()).collect()),
+            Expr::Or(list) => Expr::Or(list.iter().map(|e| e.to_ascii_lowercase()).collect()),
+            Expr::Not(e) => Expr::Not(Box::new(e.to_ascii_lowercase())),
+        }
+    }
+
+    pub fn eval(&self, text:&[u8]) -> bool {
+        match self {
+            Expr::Term(t) => memmem::find(text, t).is_some(),
+            Expr::And(list) => list.iter().all(|e| e.eval(text)),
+            Expr::Or(list) => list.iter().any(|e| e.eval(text)),
+            Expr::Not(e) => !e.eval(text),
+        }
+    }
+
+    // terms which are not under NOT
+    pub fn positive_terms(&self) -> Vec<&[u8]> {
+        match self {
+            Expr::Term(t) => vec![t.as_slice()],
+            Expr::And(list) | Expr::Or(list) => list.iter().flat_map(|e| e.positive_terms()).collect(),
+            Expr::Not(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{Index, Ngrams};
+
+    fn term(s:&str) -> Expr {
+        Expr::Term(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Expr::parse(r#"error AND (disk OR nvme) AND NOT "debug""#).unwrap(),
+            Expr::And(vec![term("error"), Expr::Or(vec![term("disk"), term("nvme")]), Expr::Not(Box::new(term("debug")))]));
+        assert_eq!(Expr::parse("a b OR c").unwrap(), Expr::Or(vec![Expr::And(vec![term("a"), term("b")]), term("c")]));
+        assert_eq!(Expr::parse(r#""AND" "x \" y""#).unwrap(), Expr::And(vec![term("AND"), term("x \" y")]));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(Expr::parse("(a OR b").is_err());
+        assert!(Expr::parse("a OR").is_err());
+        assert!(Expr::parse("a )").is_err());
+        assert!(Expr::parse("\"abc").is_err());
+        assert!(Expr::parse("").is_err());
+    }
+
+    #[test]
+    fn test_eval() {
+        let expr = Expr::parse(r#"error AND (disk OR nvme) AND NOT "debug""#).unwrap();
+        assert!(expr.eval(b"error on nvme0"));
+        assert!(!expr.eval(b"error on nvme0 debug"));
+        assert!(!expr.eval(b"error on sda"));
+        assert_eq!(expr.positive_terms(), vec![&b"error"[..], b"disk", b"nvme"]);
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let expr = Expr::parse("error AND (disk OR nvme) AND NOT debug").unwrap();
+        let mut index = Index::new(Ngrams::default(), 0, 0, 0);
+        index.fill(Ngrams::default(), b"error nvme");
+        assert!(expr.prefilter(Case::Sensitive).matches(&index, Ngrams::default()));
+        let mut index = Index::new(Ngrams::default(), 0, 0, 0);
+        index.fill(Ngrams::default(), b"error sda");
+        assert!(!expr.prefilter(Case::Sensitive).matches(&index, Ngrams::default()));
+        assert!(Expr::parse("NOT debug").unwrap().prefilter(Case::Sensitive).is_all());
+    }
+}
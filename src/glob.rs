@@ -12,33 +12,36 @@ macro_rules! conditional_log {
 /// # Arguments
 ///
 /// * `text` - Target text to search. Any type with Index traint can be accepted.
-/// * `pattern` - Glob pattern. E,g, ["abc","def"] means abc*def
+/// * `patterns` - Glob pattern. E,g, ["abc","def"] means abc*def
 /// * `eol` - End of line value. E.g., plain ASCII text case, \x0d \x0a are meaning.`
 ///
 /// # Example
 ///
 /// ```
-/// let text = "abbabababcdef";
-/// let result = lined_glob(&text,["abc","def"],['\x0d','\x0a']);
-/// assert_eq!(result, (7,13));
+/// use mmsearch::lined_glob;
+///
+/// let text = b"abbab\nababcdef\n";
+/// let patterns: [&[u8]; 2] = [b"abc", b"def"];
+/// let result = lined_glob(&text[..], &patterns, b"\n");
+/// assert_eq!(result, Some((6,15)));
 /// ```
 ///
 /// # Returns
 ///
-/// This function returns the pair of usize means start and end.
+/// This function returns the pair of usize means start and end of the first matched line, None when no line matches.
 ///
-pub fn lined_glob<T>(text:&[T],patterns:&Vec<&[T]>,eol:&[T]) -> Option<(usize,usize)>
+pub fn lined_glob<T>(text:&[T],patterns:&[&[T]],eol:&[T]) -> Option<(usize,usize)>
 where
     T: PartialEq + Eq
 {
     let mut pattern_idx = 0;
     let mut pattern_elm_idx = 0;
     let mut line_start = 0;
-    let mut line_end = 0;
-
-    for (text_elm_idx,text_elm_val) in text.iter().enumerate() 
+    let mut pattern_start = 0; // text position where the selected pattern started matching
+    let mut text_elm_idx = 0;
 
-    {
+    while text_elm_idx < text.len() {
+        let text_elm_val = &text[text_elm_idx];
         let selected_pattern = patterns[pattern_idx];
         match text_elm_val {
             // EOL make terminate all matching and go next text_pos
@@ -50,24 +53,20 @@ where
             // the pattern is matched partially
             x if *x==selected_pattern[pattern_elm_idx] => {
                 conditional_log!("char is match");
+                if pattern_elm_idx == 0 {
+                    pattern_start = text_elm_idx;
+                }
                 // the pattern is matched complete
                 if pattern_elm_idx == selected_pattern.len() - 1 {
                     conditional_log!("the pattern {} is end",pattern_idx);
                     // last pattern is last
                     if pattern_idx == patterns.len() - 1 {
                         conditional_log!("patterns are end");
-                        // find next eol
-                        line_end = 12345678;
-                        for (i,v) in text[text_elm_idx+1..].iter().enumerate() {
-                            if eol.contains(v) {
-                                conditional_log!("found EOL");
-                                line_end = i+text_elm_idx+2;
-                            }
+                        // find next eol, no eol found, then end of text is end
+                        let line_end = match text[text_elm_idx+1..].iter().position(|v| eol.contains(v)) {
+                            Some(i) => { conditional_log!("found EOL"); i+text_elm_idx+2 },
+                            None => text.len(),
                         };
-                        // no eol found, then end of text is end
-                        if line_end == 12345678 {
-                            line_end = text.len();
-                        }
                         return Some((line_start,line_end));
                     } else {
                         // selected pattern matching is finished, go next pattern
@@ -79,18 +78,52 @@ where
                     // continue selected pattern macthing
                     conditional_log!("non match chat");
                     pattern_elm_idx += 1;
-                } 
+                }
             },
-            // x doesn't match non glob mode
-            _ => {
+            // x doesn't match non glob mode, retry the selected pattern from next of its start
+            _ if pattern_elm_idx > 0 => {
                 pattern_elm_idx = 0;
-            }
+                text_elm_idx = pattern_start + 1;
+                continue;
+            },
+            _ => {}
         }
+        text_elm_idx += 1;
     };
     None
 }
 
 
+/// Search every line matching given glob pattern.
+///
+/// Same as `lined_glob`, but continues after each matched line.
+///
+/// # Returns
+///
+/// This function returns start and end of every matched line, in text order.
+///
+pub fn lined_glob_all<T>(text:&[T],patterns:&[&[T]],eol:&[T]) -> Vec<(usize,usize)>
+where
+    T: PartialEq + Eq
+{
+    let mut pos = 0;
+    let mut lines = Vec::new();
+    while let Some((start,end)) = lined_glob(&text[pos..], patterns, eol) {
+        lines.push((pos+start,pos+end));
+        pos += end;
+    };
+    lines
+}
+
+/// Split glob string into its literal segments. E.g., "abc*def" gives ["abc","def"].
+///
+/// Empty segments of leading, trailing or repeated `*` are dropped.
+///
+pub fn glob_segments(glob:&str) -> Vec<&[u8]> {
+    glob.as_bytes().split(|x| *x==b'*').filter(|s| !s.is_empty()).collect()
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*; // Import everything from the outer module
@@ -150,6 +183,32 @@ mod tests {
         let eol = [0];
         assert_eq!(lined_glob(&text,&pat,&eol[..]),Some((3,11)));
     }
+    #[test]
+    fn test_partial_match_retry() {
+        let text = [1,1,2,0];
+        let pat_1 = [1,2];
+        let pat = vec![&pat_1[..]];
+        let eol = [0];
+        assert_eq!(lined_glob(&text,&pat,&eol[..]),Some((0,4)));
+    }
+
+    #[test]
+    fn test_all_lines() {
+        let text = [1,2,0,3,0,1,5,2,0,1];
+        let pat_1 = [1];
+        let pat_2 = [2];
+        let pat = vec![&pat_1[..],&pat_2[..]];
+        let eol = [0];
+        assert_eq!(lined_glob_all(&text,&pat,&eol[..]),vec![(0,3),(5,9)]);
+    }
+
+    #[test]
+    fn test_glob_segments() {
+        assert_eq!(glob_segments("abc*def"),vec![&b"abc"[..],&b"def"[..]]);
+        assert_eq!(glob_segments("*abc**def*"),vec![&b"abc"[..],&b"def"[..]]);
+        assert!(glob_segments("**").is_empty());
+    }
+
     /* 
     #[test]
     #[should_panic]
@@ -0,0 +1,320 @@
+use crate::error::{Error, Result};
+use crate::index::Ngrams;
+use crate::timestamp::TimeFormat;
+
+// fixed size header placed in front of the serialized ListofIndex
+//
+//  0..8   magic "MMSINDEX"
+//  8..12  format version (little endian)
+// 12..16  chunk size (little endian)
+// 16      hash function id
+// 17      n-gram length
+// 18      codec id
+// 19      flags, bit 0: chunks are aligned to record delimiter, bit 1: case folded trigram bitmaps
+// 20      record delimiter
+// 21      timestamp format id, 0: no per-chunk time range
+// 22      bits of the n-gram hash, bitmaps have 2^bits bits
+// 23..32  reserved, zero
+//
+// HEADER_SIZE is kept a multiple of 16 so the rkyv bytes that follow stay aligned.
+pub const MAGIC: [u8; 8] = *b"MMSINDEX";
+pub const FORMAT_VERSION: u32 = 11;
+// oldest format whose chunks are framed, reindex upgrades them from their chunks
+pub const FRAMED_SINCE: u32 = 8;
+pub const HEADER_SIZE: usize = 32;
+
+// fixed size footer at the end of a single-file .mms archive, pointing to the embedded index
+//
+//  0..8   magic "MMSFOOT\0"
+//  8..16  offset of the index header in the archive (little endian)
+// 16..24  length of the index, header included (little endian)
+pub const FOOTER_MAGIC: [u8; 8] = *b"MMSFOOT\0";
+pub const FOOTER_SIZE: usize = 24;
+
+// frame in front of every compressed chunk, so chunks can be found and indexed again without the index
+// the archive starts with a copy of the index header, the frames of its chunks follow one after another
+//
+//  0..4   magic "MMSC"
+//  4..8   compressed size (little endian)
+//  8..12  original size (little endian)
+// 12..16  position of the original file in the manifest (little endian)
+// 16..24  byte offset of the chunk in the original file (little endian)
+// 24..28  xxHash32 of the compressed bytes (little endian)
+// 28..32  xxHash32 of the original bytes (little endian)
+// 32..40  modification time of the original file (little endian)
+// 40..42  length of the name of the original file (little endian), the name follows
+//
+// a chunk which does not shrink by compression is stored raw, with equal sizes and checksums
+pub const FRAME_MAGIC: [u8; 4] = *b"MMSC";
+pub const FRAME_SIZE: usize = 42;
+
+pub const HASH_3_TO_2: u8 = 1;    // trigrams to 16 bits, the default
+pub const HASH_MULTIPLY: u8 = 2;  // n-grams of 3 to 8 bytes to 10 to 20 bits
+pub const CODEC_LZ4_BLOCK: u8 = 1;
+
+pub const FLAG_ALIGNED: u8 = 1;
+pub const FLAG_FOLDED: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub version: u32,
+    pub chunk_size: u32,
+    pub hash_fn: u8,
+    pub ngram: u8,
+    pub hash_bits: u8,
+    pub codec: u8,
+    pub delimiter: Option<u8>, // None means chunks are cut at exact chunk size
+    pub folded: bool,          // every Index has an ASCII case folded trigram bitmap
+    pub time_format: Option<TimeFormat>, // every Index has the time range of its lines
+}
+
+impl Header {
+    pub fn new(chunk_size: usize) -> Header {
+        Header {
+            version: FORMAT_VERSION,
+            chunk_size: chunk_size as u32,
+            hash_fn: HASH_3_TO_2,
+            ngram: 3,
+            hash_bits: 16,
+            codec: CODEC_LZ4_BLOCK,
+            delimiter: Some(b'\n'),
+            folded: false,
+            time_format: None,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.chunk_size.to_le_bytes());
+        bytes[16] = self.hash_fn;
+        bytes[17] = self.ngram;
+        bytes[18] = self.codec;
+        if let Some(d) = self.delimiter {
+            bytes[19] |= FLAG_ALIGNED;
+            bytes[20] = d;
+        }
+        if self.folded {
+            bytes[19] |= FLAG_FOLDED;
+        }
+        bytes[21] = self.time_format.map_or(0, TimeFormat::id);
+        bytes[22] = self.hash_bits;
+        bytes
+    }
+
+    pub fn ngrams(&self) -> Ngrams {
+        Ngrams { len: self.ngram as usize, bits: self.hash_bits as u32, hash_fn: self.hash_fn }
+    }
+
+    // header of an archive of a format with framed chunks, taken as the current version
+    // format 8 had no hash width, its trigrams were hashed to 16 bits
+    // chunks of formats before 10 are never raw, see ChunkFrame::raw
+    pub fn from_bytes_upgrade(bytes: &[u8]) -> Result<Header> {
+        let mut bytes = bytes.to_vec();
+        if bytes.len() >= HEADER_SIZE && bytes[0..8] == MAGIC {
+            let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+            if (FRAMED_SINCE..FORMAT_VERSION).contains(&version) {
+                bytes[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+                if version==FRAMED_SINCE {
+                    bytes[22] = 16;
+                }
+            }
+        }
+        Header::from_bytes(&bytes)
+    }
+
+    // parse and validate a header, any mismatch is reported as a corrupt index
+    pub fn from_bytes(bytes: &[u8]) -> Result<Header> {
+        if bytes.len() < HEADER_SIZE || bytes[0..8] != MAGIC {
+            return Err(invalid("not an mmsearch index file (bad magic)".to_string()));
+        }
+        let header = Header {
+            version: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            chunk_size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            hash_fn: bytes[16],
+            ngram: bytes[17],
+            hash_bits: bytes[22],
+            codec: bytes[18],
+            delimiter: if bytes[19] & FLAG_ALIGNED != 0 { Some(bytes[20]) } else { None },
+            folded: bytes[19] & FLAG_FOLDED != 0,
+            time_format: TimeFormat::from_id(bytes[21]),
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(Error::Version { found: header.version, expected: FORMAT_VERSION });
+        }
+        if header.chunk_size == 0 || !header.chunk_size.is_power_of_two() {
+            return Err(invalid(format!("invalid chunk size {} in index header", header.chunk_size)));
+        }
+        header.check_ngrams().map_err(invalid)?;
+        if header.codec != CODEC_LZ4_BLOCK {
+            return Err(invalid(format!("unknown codec id {} in index header", header.codec)));
+        }
+        if bytes[21] != 0 && header.time_format.is_none() {
+            return Err(invalid(format!("unknown timestamp format id {} in index header", bytes[21])));
+        }
+        Ok(header)
+    }
+
+    // the hash function supports the n-gram length and the hash width
+    pub fn check_ngrams(&self) -> std::result::Result<(), String> {
+        match self.hash_fn {
+            HASH_3_TO_2 if self.ngram==3 && self.hash_bits==16 => Ok(()),
+            HASH_MULTIPLY if (3..=8).contains(&self.ngram) && (10..=20).contains(&self.hash_bits) => Ok(()),
+            HASH_3_TO_2 | HASH_MULTIPLY => Err(format!("unsupported n-gram length {} with {} hash bits", self.ngram, self.hash_bits)),
+            _ => Err(format!("unknown hash function id {} in index header", self.hash_fn)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footer {
+    pub index_offset: u64,
+    pub index_len: u64,
+}
+
+impl Footer {
+    pub fn to_bytes(self) -> [u8; FOOTER_SIZE] {
+        let mut bytes = [0u8; FOOTER_SIZE];
+        bytes[0..8].copy_from_slice(&FOOTER_MAGIC);
+        bytes[8..16].copy_from_slice(&self.index_offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.index_len.to_le_bytes());
+        bytes
+    }
+
+    // returns None when bytes are not a footer, i.e. the file has no embedded index
+    pub fn from_bytes(bytes: &[u8]) -> Option<Footer> {
+        if bytes.len() != FOOTER_SIZE || bytes[0..8] != FOOTER_MAGIC {
+            return None;
+        }
+        Some(Footer {
+            index_offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            index_len: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkFrame {
+    pub compress_size: u32,
+    pub original_size: u32,
+    pub file: u32,
+    pub file_offset: u64,
+    pub compressed_checksum: u32,
+    pub original_checksum: u32,
+    pub mtime: i64,
+    pub name: String,
+}
+
+impl ChunkFrame {
+    // length of the frame, the compressed bytes start after it
+    pub fn size(&self) -> usize {
+        FRAME_SIZE + self.name.len().min(u16::MAX as usize)
+    }
+
+    // a chunk stored without compression, its stored bytes are its original bytes
+    pub fn raw(&self) -> bool {
+        self.compress_size==self.original_size && self.compressed_checksum==self.original_checksum
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = &self.name.as_bytes()[0..self.size() - FRAME_SIZE];
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend_from_slice(&FRAME_MAGIC);
+        bytes.extend_from_slice(&self.compress_size.to_le_bytes());
+        bytes.extend_from_slice(&self.original_size.to_le_bytes());
+        bytes.extend_from_slice(&self.file.to_le_bytes());
+        bytes.extend_from_slice(&self.file_offset.to_le_bytes());
+        bytes.extend_from_slice(&self.compressed_checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.original_checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime.to_le_bytes());
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(name);
+        bytes
+    }
+
+    // parse the fixed part of a frame, returns it with an empty name and the length of the name
+    // None when bytes are not a frame
+    pub fn from_bytes(bytes: &[u8]) -> Option<(ChunkFrame, usize)> {
+        if bytes.len() < FRAME_SIZE || bytes[0..4] != FRAME_MAGIC {
+            return None;
+        }
+        let frame = ChunkFrame {
+            compress_size: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            original_size: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            file: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            file_offset: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            compressed_checksum: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            original_checksum: u32::from_le_bytes(bytes[28..32].try_into().unwrap()),
+            mtime: i64::from_le_bytes(bytes[32..40].try_into().unwrap()),
+            name: String::new(),
+        };
+        Some((frame, u16::from_le_bytes(bytes[40..42].try_into().unwrap()) as usize))
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::CorruptIndex(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut header = Header::new(8 * 1024 * 1024);
+        assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+        header.delimiter = None;
+        header.folded = true;
+        header.time_format = Some(TimeFormat::Syslog);
+        assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+        header.hash_fn = HASH_MULTIPLY;
+        header.ngram = 4;
+        header.hash_bits = 18;
+        assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+        header.hash_fn = HASH_3_TO_2;
+        assert!(matches!(Header::from_bytes(&header.to_bytes()), Err(Error::CorruptIndex(_))));
+    }
+
+    #[test]
+    fn test_footer_roundtrip() {
+        let footer = Footer { index_offset: 4096, index_len: 123 };
+        assert_eq!(Footer::from_bytes(&footer.to_bytes()), Some(footer));
+        assert_eq!(Footer::from_bytes(&[0u8; FOOTER_SIZE]), None);
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = ChunkFrame { compress_size: 100, original_size: 300, file: 2, file_offset: 1 << 40, compressed_checksum: 7, original_checksum: 9, mtime: -1, name: "app.log".to_string() };
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes.len(), frame.size());
+        let (mut parsed, name_len) = ChunkFrame::from_bytes(&bytes).unwrap();
+        parsed.name = String::from_utf8(bytes[FRAME_SIZE..FRAME_SIZE + name_len].to_vec()).unwrap();
+        assert_eq!(parsed, frame);
+        assert_eq!(ChunkFrame::from_bytes(&bytes[0..FRAME_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn test_foreign_file() {
+        let bytes = [0u8; HEADER_SIZE];
+        assert!(matches!(Header::from_bytes(&bytes), Err(Error::CorruptIndex(_))));
+        assert!(matches!(Header::from_bytes(b"MMSINDEX"), Err(Error::CorruptIndex(_))));
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut bytes = Header::new(4 * 1024 * 1024).to_bytes();
+        bytes[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = Header::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(err, Error::Version { found, expected: FORMAT_VERSION } if found==FORMAT_VERSION + 1));
+        assert!(Header::from_bytes_upgrade(&bytes).is_err());
+
+        // a framed archive of format 8 is read as the current version
+        let mut bytes = Header::new(4 * 1024 * 1024).to_bytes();
+        bytes[8..12].copy_from_slice(&FRAMED_SINCE.to_le_bytes());
+        bytes[22] = 0;
+        assert!(Header::from_bytes(&bytes).is_err());
+        assert_eq!(Header::from_bytes_upgrade(&bytes).unwrap(), Header::new(4 * 1024 * 1024));
+    }
+}
@@ -1,51 +1,417 @@
+use std::fs::File;
+use crate::error::{Error, Result};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use nix::libc::{self, c_void};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
 use rkyv::{Archive, Deserialize, Serialize};
+use lz4_flex::block::{decompress_into, get_maximum_output_size};
+use crate::header::{Header, Footer, HEADER_SIZE, FOOTER_SIZE, HASH_3_TO_2};
 
 // very small pseudo hash 3 bytes to 2 bytes
 pub fn hash_3_to_2(byte1: u8, byte2: u8, byte3: u8) -> u16 {
     // Use the first byte and XOR with the high bits of the second byte
     let high = u16::from(byte1) ^ (u16::from(byte2) << 4);
-    
+
     // Use the low bits of the second byte and XOR with the third byte
     let low = (u16::from(byte2) >> 4) ^ u16::from(byte3);
-    
+
     (high << 8) | low
 }
 
+// multiplicative hash of up to 8 bytes to the top bits of a u64
+pub fn hash_multiply(bytes:&[u8], bits:u32) -> usize {
+    let mut word = [0u8; 8];
+    word[0..bytes.len()].copy_from_slice(bytes);
+    (u64::from_le_bytes(word).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - bits)) as usize
+}
+
+// how the n-grams of a chunk are hashed into its bitmaps, given by the header of the archive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ngrams {
+    pub len: usize, // bytes of an n-gram
+    pub bits: u32,  // bitmaps have 2^bits bits
+    pub hash_fn: u8,
+}
+
+impl Default for Ngrams {
+    // trigrams hashed to 16 bits by hash_3_to_2
+    fn default() -> Ngrams {
+        Ngrams { len: 3, bits: 16, hash_fn: HASH_3_TO_2 }
+    }
+}
+
+impl Ngrams {
+    pub fn hash(self, gram:&[u8]) -> usize {
+        if self.hash_fn==HASH_3_TO_2 {
+            hash_3_to_2(gram[0], gram[1], gram[2]) as usize
+        } else {
+            hash_multiply(gram, self.bits)
+        }
+    }
+
+    // u64 words of a bitmap
+    pub fn words(self) -> usize {
+        (1usize << self.bits) / 64
+    }
+
+    // bytes shared between neighbouring chunks when a chunk is cut inside a record
+    pub fn overlap(self) -> usize {
+        self.len - 1
+    }
+
+    // every n-gram of bytes is in bitmap
+    pub fn contains(self, bitmap:&[u64], bytes:&[u8]) -> bool {
+        bytes.windows(self.len).all(|gram| {
+            let h = self.hash(gram);
+            bitmap[h / 64] & (1u64 << (h % 64)) != 0
+        })
+    }
+}
+
+// very small pseudo hash 2 bytes to 12 bits, for the bigram bitmap
+pub fn hash_2_to_12(byte1: u8, byte2: u8) -> u16 {
+    ((u16::from(byte1) << 4) ^ u16::from(byte2)) & 0x0fff
+}
+
+// checksum of the compressed and the original bytes of a chunk
+pub fn checksum(bytes:&[u8]) -> u32 {
+    twox_hash::XxHash32::oneshot(0, bytes)
+}
+
+// original bytes of a stored chunk written to chunk, returns their size
+// a raw chunk is stored as it is, the others are LZ4 blocks
+pub fn expand_chunk(stored:&[u8], raw:bool, chunk:&mut [u8]) -> std::result::Result<usize, String> {
+    if raw {
+        let Some(original) = chunk.get_mut(0..stored.len()) else {
+            return Err(format!("raw chunk of {} bytes is longer than its original size {}", stored.len(), chunk.len()));
+        };
+        original.copy_from_slice(stored);
+        return Ok(stored.len());
+    }
+    decompress_into(stored, chunk).map_err(|e| e.to_string())
+}
+
 // write compact bool vector to index file
 // index element
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
-#[archive(compare(PartialEq))]
+#[archive(compare(PartialEq), check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct Index {
     pub offset:u64,
     pub compress_size:u32, // it's enough by u32, but use u64 for padding
+    pub raw:bool,          // stored without compression, compression did not shrink it
     pub original_size:u32,
-    pub hash: [u64; 65536 / 64]
+    pub file:u32,         // position of the original file in ListofIndex::files
+    pub file_offset:u64,  // byte offset of the chunk in the original file
+    pub compressed_checksum:u32, // xxHash32 of the compressed bytes
+    pub original_checksum:u32,   // xxHash32 of the original bytes
+    pub hash: Vec<u64>,   // n-grams of the chunk, Ngrams::words long
+    pub unigram: [u64; 256 / 64], // every byte value of the chunk, to prune queries shorter than 3 bytes
+    pub bigram: [u64; 4096 / 64],
+    pub folded: Vec<u64>, // n-grams of ASCII lower cased chunk, empty unless created with case folding
+    pub first_line: u64,  // line number of the first line of the chunk in the original file, from 1
+    pub lines: u32,       // line endings in the chunk
+    pub cut: bool,        // the chunk ends inside a line, which goes on in the next chunk of its file if there is one
+    pub min_time: i64,    // time range of the lines in seconds since the epoch, min_time > max_time when unknown
+    pub max_time: i64,
+}
+
+impl Index {
+    pub fn new(ngrams:Ngrams, offset:u64, compress_size:u32, original_size:u32) -> Index {
+        Index { offset, compress_size, raw: false, original_size, file: 0, file_offset: 0, compressed_checksum: 0, original_checksum: 0, hash: vec![0u64; ngrams.words()], unigram: [0u64; 256 / 64], bigram: [0u64; 4096 / 64], folded: Vec::new(), first_line: 1, lines: 0, cut: false, min_time: i64::MAX, max_time: i64::MIN }
+    }
+
+    // index with case folded trigram bitmap
+    pub fn new_folded(ngrams:Ngrams, offset:u64, compress_size:u32, original_size:u32) -> Index {
+        let mut index = Index::new(ngrams, offset, compress_size, original_size);
+        index.folded = vec![0u64; ngrams.words()];
+        index
+    }
+
+    // set every n-gram of bytes into the bitmaps
+    pub fn fill(&mut self, ngrams:Ngrams, bytes:&[u8]) {
+        fill_hash(&mut self.hash, ngrams, bytes);
+        fill_short(&mut self.unigram, &mut self.bigram, bytes);
+        if !self.folded.is_empty() {
+            fill_hash_folded(&mut self.folded, ngrams, bytes);
+        }
+    }
+}
+
+// bitmaps of a chunk used by the query prefilter
+pub trait ChunkBits {
+    fn trigram(&self) -> &[u64];
+    fn unigram(&self) -> &[u64];
+    fn bigram(&self) -> &[u64];
+    fn folded(&self) -> &[u64];
+}
+
+impl ChunkBits for Index {
+    fn trigram(&self) -> &[u64] { &self.hash }
+    fn unigram(&self) -> &[u64] { &self.unigram }
+    fn bigram(&self) -> &[u64] { &self.bigram }
+    fn folded(&self) -> &[u64] { &self.folded }
+}
+
+impl ChunkBits for ArchivedIndex {
+    fn trigram(&self) -> &[u64] { &self.hash }
+    fn unigram(&self) -> &[u64] { &self.unigram }
+    fn bigram(&self) -> &[u64] { &self.bigram }
+    fn folded(&self) -> &[u64] { &self.folded }
+}
+
+// bitmaps of neighbouring chunks or-ed together, for a line running from one chunk into the next
+pub struct MergedBits {
+    hash: Vec<u64>,
+    unigram: [u64; 256 / 64],
+    bigram: [u64; 4096 / 64],
+    folded: Vec<u64>,
+}
+
+impl MergedBits {
+    pub fn of(chunks:&[&ArchivedIndex]) -> MergedBits {
+        let or = |bitmaps:Vec<&[u64]>| (0..bitmaps[0].len()).map(|i| bitmaps.iter().fold(0, |word, bitmap| word | bitmap[i])).collect::<Vec<u64>>();
+        MergedBits {
+            hash: or(chunks.iter().map(|chunk| chunk.trigram()).collect()),
+            unigram: or(chunks.iter().map(|chunk| chunk.unigram()).collect()).try_into().unwrap(),
+            bigram: or(chunks.iter().map(|chunk| chunk.bigram()).collect()).try_into().unwrap(),
+            // chunks of an archive all have folded bitmaps or none
+            folded: or(chunks.iter().map(|chunk| chunk.folded()).collect()),
+        }
+    }
+}
+
+impl ChunkBits for MergedBits {
+    fn trigram(&self) -> &[u64] { &self.hash }
+    fn unigram(&self) -> &[u64] { &self.unigram }
+    fn bigram(&self) -> &[u64] { &self.bigram }
+    fn folded(&self) -> &[u64] { &self.folded }
 }
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
-#[archive(compare(PartialEq))]
+#[archive(compare(PartialEq), check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct ListofIndex {
     pub n:u32,
+    pub files:Vec<FileEntry>, // manifest of the original files, in the order their chunks are stored
     pub indexies:Vec<Index>
 }
 
-pub fn fill_index(index:&mut Index,v:&Vec<bool>) {
-    let hash_bytes = unsafe {
-        std::slice::from_raw_parts_mut(
-            index.hash.as_mut_ptr() as *mut u8,
-            (65536/64) * std::mem::size_of::<u64>(),
-        )
-    };
+// an original file of the archive
+// a file renamed away by log rotation and the new file at the same path are separate entries
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive(compare(PartialEq), check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct FileEntry {
+    pub name:String,
+    pub size:u64,  // bytes archived, a growing file may be longer
+    pub mtime:i64, // modification time in seconds since the epoch when it was last archived
+}
+
+// set every n-gram hash of bytes into the bitmap
+pub fn fill_hash(hash:&mut [u64], ngrams:Ngrams, bytes:&[u8]) {
+    bytes.windows(ngrams.len).for_each(|s| {
+        let h = ngrams.hash(s);
+        hash[h / 64] |= 1u64 << (h % 64);
+    });
+}
+
+// set every n-gram hash of ASCII lower cased bytes into the bitmap
+pub fn fill_hash_folded(hash:&mut [u64], ngrams:Ngrams, bytes:&[u8]) {
+    let mut gram = [0u8; 8];
+    bytes.windows(ngrams.len).for_each(|s| {
+        for (g, b) in gram.iter_mut().zip(s) {
+            *g = b.to_ascii_lowercase();
+        }
+        let h = ngrams.hash(&gram[0..ngrams.len]);
+        hash[h / 64] |= 1u64 << (h % 64);
+    });
+}
 
-    for i in 0..(65536/8) {
-        let mut u:u8 = 0;
-        for j in i*8..i*8+8 {
-            if v[j] { u|=1; }
-            u <<= 1;
+// set every byte and every bigram hash of bytes into the bitmaps
+pub fn fill_short(unigram:&mut [u64], bigram:&mut [u64], bytes:&[u8]) {
+    bytes.iter().for_each(|b| {
+        unigram[*b as usize / 64] |= 1u64 << (*b as usize % 64);
+    });
+    bytes.windows(2).for_each(|s| {
+        let h = hash_2_to_12(s[0], s[1]) as usize;
+        bigram[h / 64] |= 1u64 << (h % 64);
+    });
+}
+
+// memory mapped index file, header and ListofIndex are accessed in place
+pub struct IndexFile {
+    ptr: *mut c_void,
+    len: usize,
+    start: usize, // index header position inside the mapping
+    pub header: Header,
+}
+
+// SAFETY: the mapping is read-only (PROT_READ, MAP_PRIVATE) and only unmapped on drop,
+// the pointer is never written through, so it can be moved to and shared between threads
+unsafe impl Send for IndexFile {}
+unsafe impl Sync for IndexFile {}
+
+impl IndexFile {
+    // map the whole index file and validate header and rkyv structure
+    pub fn open(index:&File) -> Result<IndexFile> {
+        let len = index.metadata()?.len();
+        IndexFile::open_range(index, 0, len)
+    }
+
+    // map the index embedded in a single-file archive, None when the file has no footer
+    pub fn open_embedded(archive:&File) -> Result<Option<IndexFile>> {
+        let file_len = archive.metadata()?.len();
+        if file_len < FOOTER_SIZE as u64 {
+            return Ok(None);
+        }
+        let mut footer_bytes = [0u8; FOOTER_SIZE];
+        archive.read_exact_at(&mut footer_bytes, file_len - FOOTER_SIZE as u64)?;
+        let footer = match Footer::from_bytes(&footer_bytes) {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        log::debug!("footer: {:?}", footer);
+        if footer.index_offset.checked_add(footer.index_len) != Some(file_len - FOOTER_SIZE as u64) {
+            return Err(Error::CorruptIndex("footer does not point to the embedded index".to_string()));
+        }
+        IndexFile::open_range(archive, footer.index_offset, footer.index_len).map(Some)
+    }
+
+    fn open_range(file:&File, offset:u64, len:u64) -> Result<IndexFile> {
+        if len < HEADER_SIZE as u64 {
+            return Err(Error::CorruptIndex("index file is too short to be an mmsearch index".to_string()));
+        }
+        // mmap offset must be page aligned, map from the page containing the index
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let map_offset = offset & !(page - 1);
+        let start = (offset - map_offset) as usize;
+        let map_len = start + len as usize;
+        let ptr = unsafe {
+            mmap(None, NonZeroUsize::new(map_len).unwrap(), ProtFlags::PROT_READ, MapFlags::MAP_PRIVATE, Some(file), map_offset as i64)?
+        };
+        let mut mapped = IndexFile { ptr, len: map_len, start, header: Header::new(0) };
+        mapped.header = Header::from_bytes(mapped.bytes())?;
+        log::debug!("index header: {:?}", mapped.header);
+
+        // structural check, a truncated or corrupted index is rejected here instead of crashing later
+        if let Err(e) = rkyv::check_archived_root::<ListofIndex>(&mapped.bytes()[HEADER_SIZE..]) {
+            return Err(Error::CorruptIndex(e.to_string()));
+        }
+        if mapped.list().n as usize != mapped.list().indexies.len() {
+            return Err(Error::CorruptIndex("entry count mismatch".to_string()));
         }
-        hash_bytes[i] = u;
-    };
-    log::debug!("{:x?}",hash_bytes);
+        let files = mapped.list().files.len();
+        if mapped.list().indexies.iter().any(|ielm| ielm.file as usize >= files) {
+            return Err(Error::CorruptIndex("chunk of a file not in the manifest".to_string()));
+        }
+        // readers allocate chunk buffers of these sizes, they are bounded by the chunk size
+        let chunk_size = mapped.header.chunk_size;
+        if let Some((id, _)) = mapped.list().indexies.iter().enumerate().find(|(_, ielm)|
+                ielm.original_size > chunk_size || ielm.compress_size as usize > get_maximum_output_size(chunk_size as usize)) {
+            return Err(Error::CorruptIndex(format!("chunk {} is larger than the chunk size {}", id, chunk_size)));
+        }
+        // searches look n-grams up in the bitmaps by their hash, they hold a word for every 64 hashes
+        let words = mapped.header.ngrams().words();
+        if let Some((id, _)) = mapped.list().indexies.iter().enumerate().find(|(_, ielm)|
+                ielm.hash.len() != words || !(ielm.folded.is_empty() || ielm.folded.len()==words)) {
+            return Err(Error::CorruptIndex(format!("bitmaps of chunk {} are not {} words long", id, words)));
+        }
+        log::info!("index: {:?}, len of index bytes: {:?}", file, len);
+        Ok(mapped)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts((self.ptr as *const u8).add(self.start), self.len - self.start) }
+    }
+
+    pub fn list(&self) -> &ArchivedListofIndex {
+        // validated by open()
+        unsafe { rkyv::archived_root::<ListofIndex>(&self.bytes()[HEADER_SIZE..]) }
+    }
+}
+
+// use the index embedded in the archive if any, otherwise the separate index file
+pub fn open_index(archive:&File, index_path:&Path) -> Result<IndexFile> {
+    match IndexFile::open_embedded(archive)? {
+        Some(index_file) => {
+            log::info!("use index embedded in the archive");
+            Ok(index_file)
+        },
+        None => IndexFile::open(&File::open(index_path)?),
+    }
+}
+
+impl Drop for IndexFile {
+    fn drop(&mut self) {
+        unsafe { let _ = munmap(self.ptr, self.len); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_file;
+    use std::io::Write;
+
+    fn write_index(name:&str, truncate:usize) -> File {
+        write_chunk_index(name, truncate, Index::new(Ngrams::default(), 0, 3, 3))
+    }
+
+    fn write_chunk_index(name:&str, truncate:usize, ielm:Index) -> File {
+        let files = vec![FileEntry { name: "a.log".to_string(), size: 3, mtime: 0 }];
+        let list = ListofIndex { n: 1, files, indexies: vec![ielm] };
+        let bytes = rkyv::to_bytes::<_, 256>(&list).unwrap();
+        let mut f = temp_file(name);
+        f.write_all(&Header::new(4 * 1024 * 1024).to_bytes()).unwrap();
+        f.write_all(&bytes[..bytes.len() - truncate]).unwrap();
+        f
+    }
+
+    #[test]
+    fn test_open_valid() {
+        let f = write_index("valid", 0);
+        let index_file = IndexFile::open(&f).unwrap();
+        assert_eq!(index_file.list().n, 1);
+        assert_eq!(index_file.list().indexies[0].original_size, 3);
+    }
+
+    #[test]
+    fn test_open_oversized_chunk() {
+        // a chunk size over the bound of lz4 output would make searches allocate it
+        let f = write_chunk_index("oversized", 0, Index::new(Ngrams::default(), 0, u32::MAX, 3));
+        assert!(matches!(IndexFile::open(&f), Err(Error::CorruptIndex(_))));
+        let f = write_chunk_index("oversized-original", 0, Index::new(Ngrams::default(), 0, 3, 8 * 1024 * 1024));
+        assert!(matches!(IndexFile::open(&f), Err(Error::CorruptIndex(_))));
+    }
+
+    #[test]
+    fn test_open_short_bitmap() {
+        // a bitmap shorter than the header says would make the prefilter index past its end
+        let mut ielm = Index::new(Ngrams::default(), 0, 3, 3);
+        ielm.hash.truncate(10);
+        let f = write_chunk_index("short-hash", 0, ielm);
+        assert!(matches!(IndexFile::open(&f), Err(Error::CorruptIndex(_))));
+        let mut ielm = Index::new_folded(Ngrams::default(), 0, 3, 3);
+        ielm.folded.pop();
+        let f = write_chunk_index("short-folded", 0, ielm);
+        assert!(matches!(IndexFile::open(&f), Err(Error::CorruptIndex(_))));
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn shared<T: Send + Sync>(_:&T) {}
+        let index_file = IndexFile::open(&write_index("send", 0)).unwrap();
+        shared(&index_file);
+        let n = std::thread::scope(|s| s.spawn(|| index_file.list().n).join().unwrap());
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn test_open_truncated() {
+        let f = write_index("truncated", 20);
+        assert!(matches!(IndexFile::open(&f), Err(Error::CorruptIndex(_))));
+    }
 }
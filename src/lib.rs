@@ -0,0 +1,34 @@
+// MakiMaxiSearch, compressed archives of logs with a trigram index per chunk
+//
+// ArchiveWriter creates archives and appends to them, ArchiveFile opens one for reading,
+// Searcher finds lines of archives and ArchiveReader reads an archive as its original bytes
+mod archive;
+mod create_files;
+mod error;
+mod expand_files;
+mod expr;
+mod glob;
+mod header;
+mod index;
+mod prefilter;
+mod query;
+mod reader;
+mod repair;
+#[cfg(test)]
+mod test_util;
+mod timestamp;
+mod verify;
+mod watch;
+
+pub use archive::ArchiveFile;
+pub use create_files::{ArchiveWriter, Source};
+pub use error::{Error, Result};
+pub use expr::Scope;
+pub use glob::{glob_segments, lined_glob, lined_glob_all};
+pub use header::{Header, FORMAT_VERSION};
+pub use prefilter::Case;
+pub use query::{Hit, Hits, OutputFormat, Query, Searcher};
+pub use reader::ArchiveReader;
+pub use repair::Repaired;
+pub use timestamp::{parse_time_arg, TimeFormat, TimeWindow, TIME_FORMATS};
+pub use watch::watch;
@@ -1,25 +1,105 @@
-mod create_files;
-mod expand_files;
-mod query;
-mod index;
-mod glob;
-
-use create_files::create_files;
-use expand_files::expand_file;
+use mmsearch::{parse_time_arg, ArchiveFile, ArchiveWriter, Case, Error, OutputFormat, Query, Result, Scope, Searcher, Source, TimeFormat, TimeWindow, TIME_FORMATS};
 
 #[allow(unused_imports)]
 use log::{info, warn, Level};
 use simple_logger::SimpleLogger;
 
 // args
-use clap::{arg, command, value_parser};
-use std::os::fd::AsRawFd;
+use clap::{arg, command, value_parser, ArgAction, ArgGroup, ArgMatches};
+use std::io::Write;
 use std::path::PathBuf;
 
 // file operation for create index
 use std::fs;
 
-fn main() -> std::io::Result<()> {
+// settings of a new archive from --delimiter, --fold, --timestamps and --threads
+fn writer_of(subcommand:&ArgMatches, chunk_size:usize) -> Result<ArchiveWriter> {
+    Ok(ArchiveWriter::new()
+        .chunk_size(chunk_size)
+        .delimiter(parse_delimiter(subcommand.get_one::<String>("delimiter").unwrap())?)
+        .fold(subcommand.get_flag("fold"))
+        .timestamps(subcommand.get_one::<String>("timestamps").map(|name| TimeFormat::from_name(name)).transpose()?)
+        .threads(threads_of(subcommand)))
+}
+
+// record delimiter given on command line, None disables line alignment
+fn parse_delimiter(s:&str) -> Result<Option<u8>> {
+    match s {
+        "none" => Ok(None),
+        "\\n" => Ok(Some(b'\n')),
+        "\\r" => Ok(Some(b'\r')),
+        "\\t" => Ok(Some(b'\t')),
+        "\\0" => Ok(Some(0)),
+        _ if s.len()==1 => Ok(Some(s.as_bytes()[0])),
+        _ if s.starts_with("0x") => u8::from_str_radix(&s[2..], 16).map(Some)
+            .map_err(|_| Error::InvalidInput(format!("invalid delimiter {}", s))),
+        _ => Err(Error::InvalidInput(format!("invalid delimiter {}", s))),
+    }
+}
+
+// --source files followed by the files of --source-dir, oldest first
+fn source_paths(subcommand:&ArgMatches) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = subcommand.get_many::<PathBuf>("source").into_iter().flatten().cloned().collect();
+    if let Some(dir) = subcommand.get_one::<PathBuf>("source-dir") {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((metadata.modified()?, entry.path()));
+            }
+        }
+        files.sort();
+        paths.extend(files.into_iter().map(|(_, path)| path));
+    }
+    Ok(paths)
+}
+
+// archives of --file and --archive-dir, with the index given as ARCHIVE=INDEX
+fn archive_paths(subcommand:&ArgMatches) -> Result<Vec<(PathBuf, Option<PathBuf>)>> {
+    let mut paths = Vec::new();
+    for path in subcommand.get_many::<PathBuf>("file").into_iter().flatten() {
+        let pair = path.to_str().filter(|_| !path.exists()).and_then(|s| s.rsplit_once('='));
+        match pair {
+            Some((archive, index)) => paths.push((PathBuf::from(archive), Some(PathBuf::from(index)))),
+            None => paths.push((path.clone(), None)),
+        }
+    }
+    if let Some(dir) = subcommand.get_one::<PathBuf>("archive-dir") {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|e| e=="mms") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        paths.extend(files.into_iter().map(|path| (path, None)));
+    }
+    Ok(paths)
+}
+
+// --threads of the subcommand, all CPUs by default
+fn threads_of(subcommand:&ArgMatches) -> usize {
+    subcommand.get_one::<usize>("threads").copied()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+// exit code of each error, 2 is left to clap for usage errors
+fn exit_code(e:&Error) -> i32 {
+    match e {
+        Error::Io(_) => 1,
+        Error::InvalidInput(_) => 3,
+        Error::Query(_) => 4,
+        Error::CorruptIndex(_) => 5,
+        Error::Version { .. } => 6,
+        Error::Decompress { .. } => 7,
+        Error::SourceMismatch(_) => 8,
+        Error::Checksum { .. } => 9,
+    }
+}
+
+fn main() {
     let matches = command!()
         .subcommand_required(true)
         .author("Nobuhiko Tsuruoka, takanotume@gmail.com")
@@ -30,7 +110,7 @@ fn main() -> std::io::Result<()> {
             .value_parser(value_parser!(PathBuf))
             .default_value("index.dat"))
         .arg(arg!(-c --chunk <CHUNK>)
-            .help("chunk size should be 2^N")
+            .help("chunk size should be 2^N, used by create and recorded in the index header")
             .value_parser(["4M","8M","16M"])
             .default_value("4M"))
         .arg(arg!(-l --log <LOG>)
@@ -43,18 +123,144 @@ fn main() -> std::io::Result<()> {
                 .required(true)
                 .help("target file generating with compression"))
             .arg(arg!(-s --source <SOURCE>)
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .help("original source text file, repeat it to archive several files"))
+            .arg(arg!(--"source-dir" <DIR>)
+                .value_parser(value_parser!(PathBuf))
+                .help("archive every regular file of the directory, oldest first"))
+            .group(ArgGroup::new("sources")
+                .args(["source", "source-dir"])
+                .multiple(true)
+                .required(true))
+            .arg(arg!(-d --delimiter <DELIMITER>)
+                .default_value("\\n")
+                .help("record delimiter chunks are aligned to: a character, \\n, \\r, \\t, \\0, 0xNN, or none for exact chunk size cuts"))
+            .arg(arg!(-S --single)
+                .help("embed the index into target as one self-contained .mms file, --index is not used"))
+            .arg(arg!(-F --fold)
+                .help("also index ASCII case folded trigrams, so search -i prunes as well as a case-sensitive search"))
+            .arg(arg!(--timestamps <FORMAT>)
+                .value_parser(TIME_FORMATS)
+                .help("record the time range of the lines of every chunk, for search --since and --until"))
+            .arg(arg!(--threads <THREADS>)
+                .value_parser(value_parser!(usize))
+                .help("worker threads hashing and compressing chunks [default: number of CPUs]")))
+        .subcommand(command!("append")
+            .about("archive the bytes source got since the last create or append")
+            .arg(arg!(-t --target <TARGET>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("compressed file, or .mms file with embedded index, created from source"))
+            .arg(arg!(-s --source <SOURCE>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("growing source text file"))
+            .arg(arg!(--"new-segment")
+                .help("source is a new file after a log rotation, archive it from its start after the existing chunks"))
+            .arg(arg!(--threads <THREADS>)
+                .value_parser(value_parser!(usize))
+                .help("worker threads hashing and compressing chunks [default: number of CPUs]")))
+        .subcommand(command!("watch")
+            .about("follow a live log file and append its new lines to the archive")
+            .arg(arg!(-t --target <TARGET>)
                 .value_parser(value_parser!(PathBuf))
                 .required(true)
-                .help("original source text file")))
+                .help("compressed file, or .mms file with embedded index, created empty when missing"))
+            .arg(arg!(-s --source <SOURCE>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("live source text file, followed through rename and truncate rotations"))
+            .arg(arg!(--interval <SECONDS>)
+                .value_parser(value_parser!(u64))
+                .default_value("5")
+                .help("append new lines at least this often, a filled chunk is appended at once"))
+            .arg(arg!(-d --delimiter <DELIMITER>)
+                .default_value("\\n")
+                .help("record delimiter of a new archive"))
+            .arg(arg!(-S --single)
+                .help("create a new archive as one self-contained .mms file"))
+            .arg(arg!(-F --fold)
+                .help("index ASCII case folded trigrams in a new archive"))
+            .arg(arg!(--timestamps <FORMAT>)
+                .value_parser(TIME_FORMATS)
+                .help("record the time range of chunks in a new archive"))
+            .arg(arg!(--threads <THREADS>)
+                .value_parser(value_parser!(usize))
+                .help("worker threads hashing and compressing chunks [default: number of CPUs]")))
         .subcommand(command!("search")
             .arg(arg!(-q --query <QUERY>)
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
-                .required(true)
                 .help("query string"))
+            .arg(arg!(-g --glob <GLOB>)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("glob pattern matched within one line, e.g. abc*def"))
+            .arg(arg!(-r --regex <REGEX>)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("regular expression matched within one line"))
+            .arg(arg!(-e --expr <EXPR>)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("boolean query, e.g. error AND (disk OR nvme) AND NOT \"debug\""))
+            .arg(arg!(--scope <SCOPE>)
+                .value_parser(["line", "chunk"])
+                .default_value("line")
+                .help("terms of --expr are all found on the same line, or in the same chunk"))
+            .group(ArgGroup::new("pattern")
+                .args(["query", "glob", "regex", "expr"])
+                .required(true))
             .arg(arg!(-f --file <FILE>)
-                .help("indexed compressed file")
+                .help("indexed compressed file, or .mms file with embedded index, ARCHIVE=INDEX for another index; repeat it to search several archives")
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append))
+            .arg(arg!(--"archive-dir" <DIR>)
                 .value_parser(value_parser!(PathBuf))
-                .required(true)))
+                .help("search every .mms file of the directory"))
+            .group(ArgGroup::new("archives")
+                .args(["file", "archive-dir"])
+                .multiple(true)
+                .required(true))
+            .arg(arg!(--"raw-chunks")
+                .help("write whole candidate chunks instead of matching lines"))
+            .arg(arg!(-b --"byte-offset")
+                .help("prefix each matching line with its byte offset in the original file"))
+            .arg(arg!(-n --"line-number")
+                .help("prefix each matching line with its line number in the original file"))
+            .arg(arg!(-H --"with-filename")
+                .help("prefix each matching line with the name of its original file, the default for archives of several files"))
+            .arg(arg!(--"no-filename")
+                .help("never prefix matching lines with file names"))
+            .arg(arg!(-i --"ignore-case")
+                .help("match ASCII letters case-insensitively"))
+            .arg(arg!(--since <TIME>)
+                .help("only lines at or after TIME: RFC3339, YYYY-MM-DD or epoch seconds, archives need --timestamps"))
+            .arg(arg!(--until <TIME>)
+                .help("only lines before TIME"))
+            .arg(arg!(--threads <THREADS>)
+                .value_parser(value_parser!(usize))
+                .help("worker threads decompressing and verifying candidate chunks [default: number of CPUs]"))
+            .arg(arg!(--"max-inflight" <CHUNKS>)
+                .value_parser(value_parser!(usize))
+                .help("candidate chunks held in memory at once [default: 4 per thread]"))
+            .arg(arg!(--"verify-checksums")
+                .help("check every chunk read against its checksums")))
+        .subcommand(command!("show")
+            .about("write lines around a line number of the original file")
+            .arg(arg!(-f --file <FILE>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("indexed compressed file, or .mms file with embedded index"))
+            .arg(arg!(--line <LINE>)
+                .value_parser(value_parser!(u64).range(1..))
+                .required(true)
+                .help("line number in the original file, from 1"))
+            .arg(arg!(--context <LINES>)
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .help("lines written before and after the line"))
+            .arg(arg!(--name <NAME>)
+                .help("original file of the manifest, needed when the archive has several files"))
+            .arg(arg!(--"verify-checksums")
+                .help("check every chunk read against its checksums")))
         .subcommand(command!("expand")
             .arg(arg!(-t --target <TARGET>)
                 .value_parser(value_parser!(PathBuf))
@@ -63,7 +269,56 @@ fn main() -> std::io::Result<()> {
             .arg(arg!(-s --source <SOURCE>)
                 .value_parser(value_parser!(PathBuf))
                 .required(true)
-                .help("original source text file")))
+                .help("original source text file"))
+            .arg(arg!(--"verify-checksums")
+                .help("check every chunk read against its checksums")))
+        .subcommand(command!("verify")
+            .about("check every chunk against its checksums, sizes and n-gram bitmaps")
+            .arg(arg!(-f --file <FILE>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("indexed compressed file, or .mms file with embedded index")))
+        .subcommand(command!("repair")
+            .about("rebuild the index from the chunks of the compressed file and cut off a torn last chunk")
+            .arg(arg!(-f --file <FILE>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("compressed file, or .mms file"))
+            .arg(arg!(-S --single)
+                .help("embed the rebuilt index into file, --index is not used")))
+        .subcommand(command!("reindex")
+            .about("index an archive again with other settings, without its original files")
+            .arg(arg!(-f --file <FILE>)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("compressed file, or .mms file, replaced in place"))
+            .arg(arg!(-S --single)
+                .help("embed the new index into file, --index is not used"))
+            .arg(arg!(--ngram <BYTES>)
+                .value_parser(value_parser!(usize))
+                .help("bytes of the n-grams in the bitmaps, 3 to 8"))
+            .arg(arg!(--"hash-bits" <BITS>)
+                .value_parser(value_parser!(u32))
+                .help("bitmaps have 2^BITS bits, 10 to 20"))
+            .arg(arg!(-F --fold)
+                .help("also index ASCII case folded n-grams"))
+            .arg(arg!(--"no-fold")
+                .conflicts_with("fold")
+                .help("drop the case folded bitmaps"))
+            .arg(arg!(--timestamps <FORMAT>)
+                .value_parser(TIME_FORMATS)
+                .help("record the time range of the lines of every chunk"))
+            .arg(arg!(--"no-timestamps")
+                .conflicts_with("timestamps")
+                .help("drop the time ranges of the chunks"))
+            .arg(arg!(--rechunk)
+                .help("cut the chunks anew with --chunk and --delimiter and compress them again"))
+            .arg(arg!(-d --delimiter <DELIMITER>)
+                .requires("rechunk")
+                .help("record delimiter the new chunks are aligned to, as for create"))
+            .arg(arg!(--threads <THREADS>)
+                .value_parser(value_parser!(usize))
+                .help("worker threads hashing and compressing chunks of --rechunk [default: number of CPUs]")))
     .get_matches();
 
     let chunk_size = match matches.get_one::<String>("chunk").unwrap().as_str() {
@@ -86,32 +341,121 @@ fn main() -> std::io::Result<()> {
     SimpleLogger::new().with_level(log_level.to_level_filter()).init().unwrap();
     log::debug!("finish argument parsing");
 
-    /*
-        This is an expermental code. Do not care of chunks divide lines
-     */
+    if let Err(e) = run(&matches, chunk_size) {
+        eprintln!("mmsearch: {}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run(matches:&ArgMatches, chunk_size:usize) -> Result<()> {
     let index_path = matches.get_one::<PathBuf>("index").unwrap();
 
     match matches.subcommand_name() {
         Some("create") => {
             let subcommand = matches.subcommand_matches("create").unwrap();
             let target_path = subcommand.get_one::<PathBuf>("target").unwrap();
+            log::debug!("target_path:{}",target_path.display());
+
+            let mut sources = source_paths(subcommand)?.iter().map(|path| Source::open(path)).collect::<Result<Vec<Source>>>()?;
+            log::info!("{} source files", sources.len());
+            let index = if subcommand.get_flag("single") { None } else { Some(index_path.as_path()) };
+            writer_of(subcommand, chunk_size)?.create(&mut sources, target_path, index)?;
+        },
+        Some("append") => {
+            let subcommand = matches.subcommand_matches("append").unwrap();
+            let target_path = subcommand.get_one::<PathBuf>("target").unwrap();
             let source_path = subcommand.get_one::<PathBuf>("source").unwrap();
             log::debug!("target_path:{}",target_path.display());
             log::debug!("source_path:{}",source_path.display());
 
-            let mut index = fs::OpenOptions::new().write(true).create(true).truncate(true).open(index_path)?;
-            let mut target = fs::OpenOptions::new().write(true).create(true).truncate(true).open(target_path)?;
-            let mut source = fs::File::open(source_path)?;
-            create_files(&mut source,&mut target,&mut index, chunk_size)?;
+            let mut source = Source::open(source_path)?;
+            ArchiveWriter::new().threads(threads_of(subcommand))
+                .append(&mut source, target_path, index_path, subcommand.get_flag("new-segment"))?;
+        },
+        Some("watch") => {
+            let subcommand = matches.subcommand_matches("watch").unwrap();
+            let target_path = subcommand.get_one::<PathBuf>("target").unwrap();
+            let source_path = subcommand.get_one::<PathBuf>("source").unwrap();
+            if !target_path.exists() {
+                let index = if subcommand.get_flag("single") { None } else { Some(index_path.as_path()) };
+                writer_of(subcommand, chunk_size)?.create_empty(target_path, index)?;
+            }
+            let interval = std::time::Duration::from_secs(*subcommand.get_one::<u64>("interval").unwrap());
+            mmsearch::watch(source_path, target_path, index_path, interval, threads_of(subcommand))?;
         },
         Some("search") => {
             let subcommand = matches.subcommand_matches("search").unwrap();
             
+            let paths = archive_paths(subcommand)?;
+            let mut archives = Vec::new();
+            for (path, index) in paths.iter() {
+                let archive = match index {
+                    Some(index) => ArchiveFile::open_with_index(path, index)?,
+                    None if paths.len()==1 => ArchiveFile::open_with_index(path, index_path)?,
+                    None => ArchiveFile::open(path).map_err(|e| match e {
+                        Error::InvalidInput(msg) => Error::InvalidInput(format!("{}, give it as {}=INDEX", msg, path.display())),
+                        e => e,
+                    })?,
+                };
+                archives.push(archive.verify_checksums(subcommand.get_flag("verify-checksums")));
+            }
+            // the indexes tell whether case folded bitmaps can prune a case-insensitive query
+            let case = if subcommand.get_flag("ignore-case") {
+                Case::Insensitive { folded_index: archives.iter().all(|archive| archive.header().folded) }
+            } else {
+                Case::Sensitive
+            };
+            let query = if let Some(query) = subcommand.get_one::<String>("query") {
+                Query::literal(query, case)
+            } else if let Some(glob) = subcommand.get_one::<String>("glob") {
+                Query::glob(glob, case)?
+            } else if let Some(regex) = subcommand.get_one::<String>("regex") {
+                Query::regex(regex, case)?
+            } else {
+                let scope = match subcommand.get_one::<String>("scope").unwrap().as_str() {
+                    "chunk" => Scope::Chunk,
+                    _ => Scope::Line,
+                };
+                Query::boolean(subcommand.get_one::<String>("expr").unwrap(), scope, case)?
+            };
+            let threads = threads_of(subcommand);
+            let since = subcommand.get_one::<String>("since").map(|s| parse_time_arg(s)).transpose()?;
+            let until = subcommand.get_one::<String>("until").map(|s| parse_time_arg(s)).transpose()?;
+            let mut searcher = Searcher::new(query)
+                .archives(archives.iter())
+                .raw_chunks(subcommand.get_flag("raw-chunks"))
+                .threads(threads)
+                .window(if since.is_some() || until.is_some() { Some(TimeWindow::new(since, until)) } else { None });
+            if let Some(max_inflight) = subcommand.get_one::<usize>("max-inflight") {
+                searcher = searcher.max_inflight(*max_inflight);
+            }
+            // results of several archives are labelled with the archive path
+            let no_filename = subcommand.get_flag("no-filename");
+            let format = OutputFormat {
+                archive_label: archives.len() > 1 && !no_filename,
+                with_filename: !no_filename && (subcommand.get_flag("with-filename") || archives.iter().any(|archive| archive.file_names().len() > 1)),
+                line_number: subcommand.get_flag("line-number"),
+                byte_offset: subcommand.get_flag("byte-offset"),
+            };
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            searcher.write_to(&mut out, format)?;
+            out.flush()?;
+        },
+        Some("show") => {
+            let subcommand = matches.subcommand_matches("show").unwrap();
             let file_path = subcommand.get_one::<PathBuf>("file").unwrap();
-            let query =  subcommand.get_one::<String>("query").unwrap();
-            let mut index = fs::File::open(index_path)?;
-            let mut file = fs::File::open(file_path)?;
-            query::query(file.as_raw_fd(), &mut index, &query.to_string(), chunk_size)?;
+            let archive = ArchiveFile::open_with_index(file_path, index_path)?.verify_checksums(subcommand.get_flag("verify-checksums"));
+            let files = archive.file_names();
+            let file = match subcommand.get_one::<String>("name") {
+                Some(name) => files.iter().position(|f| f==name)
+                    .ok_or_else(|| Error::InvalidInput(format!("{} is not a file of {}", name, file_path.display())))?,
+                None if files.len() > 1 => return Err(Error::InvalidInput(
+                    format!("{} has {} files, choose one with --name", file_path.display(), files.len()))),
+                None => 0,
+            };
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            archive.show_lines(file as u32, *subcommand.get_one::<u64>("line").unwrap(), *subcommand.get_one::<u64>("context").unwrap(), &mut out)?;
+            out.flush()?;
         },
         Some("expand") => {
             let subcommand = matches.subcommand_matches("expand").unwrap();
@@ -120,10 +464,61 @@ fn main() -> std::io::Result<()> {
             log::debug!("target_path:{}",target_path.display());
             log::debug!("source_path:{}",source_path.display());
 
-            let mut index = fs::File::open(index_path)?;
+            let archive = ArchiveFile::open_with_index(source_path, index_path)?.verify_checksums(subcommand.get_flag("verify-checksums"));
             let mut target = fs::OpenOptions::new().write(true).create(true).truncate(true).open(target_path)?;
-            let mut source = fs::File::open(source_path)?;
-            expand_file(&mut source,&mut target,&mut index, chunk_size)?;
+            archive.expand(&mut target)?;
+        },
+        Some("verify") => {
+            let subcommand = matches.subcommand_matches("verify").unwrap();
+            let file_path = subcommand.get_one::<PathBuf>("file").unwrap();
+            let archive = ArchiveFile::open_with_index(file_path, index_path)?;
+            // every problem is reported, the first one gives the exit code
+            let problems = archive.verify()?;
+            for problem in problems.iter() {
+                eprintln!("mmsearch: {}", problem);
+            }
+            if let Some(first) = problems.first() {
+                eprintln!("mmsearch: {} has {} problems", file_path.display(), problems.len());
+                std::process::exit(exit_code(first));
+            }
+        },
+        Some("repair") => {
+            let subcommand = matches.subcommand_matches("repair").unwrap();
+            let file_path = subcommand.get_one::<PathBuf>("file").unwrap();
+            let index = if subcommand.get_flag("single") { None } else { Some(index_path.as_path()) };
+            let repaired = ArchiveWriter::new().repair(file_path, index)?;
+            eprintln!("mmsearch: {} chunks indexed, {} damaged chunks skipped, {} bytes cut off after the last chunk",
+                repaired.chunks, repaired.skipped, repaired.truncated);
+        },
+        Some("reindex") => {
+            let subcommand = matches.subcommand_matches("reindex").unwrap();
+            let file_path = subcommand.get_one::<PathBuf>("file").unwrap();
+            let index = if subcommand.get_flag("single") { None } else { Some(index_path.as_path()) };
+            // settings not given are kept from the archive
+            let mut writer = ArchiveWriter::of_archive(file_path)?.threads(threads_of(subcommand));
+            let header = writer.settings();
+            if subcommand.contains_id("ngram") || subcommand.contains_id("hash-bits") {
+                let len = subcommand.get_one::<usize>("ngram").copied().unwrap_or(header.ngram as usize);
+                let bits = subcommand.get_one::<u32>("hash-bits").copied().unwrap_or(header.hash_bits as u32);
+                writer = writer.ngrams(len, bits);
+            }
+            if subcommand.get_flag("fold") || subcommand.get_flag("no-fold") {
+                writer = writer.fold(subcommand.get_flag("fold"));
+            }
+            if let Some(name) = subcommand.get_one::<String>("timestamps") {
+                writer = writer.timestamps(Some(TimeFormat::from_name(name)?));
+            } else if subcommand.get_flag("no-timestamps") {
+                writer = writer.timestamps(None);
+            }
+            let rechunk = subcommand.get_flag("rechunk");
+            if rechunk && matches.value_source("chunk")==Some(clap::parser::ValueSource::CommandLine) {
+                writer = writer.chunk_size(chunk_size);
+            }
+            if let Some(delimiter) = subcommand.get_one::<String>("delimiter") {
+                writer = writer.delimiter(parse_delimiter(delimiter)?);
+            }
+            let repaired = writer.reindex(file_path, index, rechunk)?;
+            eprintln!("mmsearch: {} chunks indexed, {} damaged chunks skipped", repaired.chunks, repaired.skipped);
         },
         Some(_) => {},
         None => {}
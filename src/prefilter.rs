@@ -0,0 +1,327 @@
+use std::collections::BTreeSet;
+use regex_syntax::hir::{Class, ClassBytes, ClassBytesRange, Hir, HirKind};
+use crate::index::{hash_2_to_12, ChunkBits, Ngrams};
+
+// limits of exact string sets while analysing a regex
+const MAX_EXACT_SET: usize = 64;
+const MAX_CLASS_SIZE: usize = 16;
+
+// boolean query of literals evaluated against the bitmaps of each chunk
+// literals are hashed with the n-grams of the archive the chunk belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Prefilter {
+    All,                    // cannot prune, every chunk is a candidate
+    Literals(Vec<Vec<u8>>), // all n-grams of the literals are required, all bytes and bigrams of literals shorter than an n-gram
+    Folded(Vec<u8>),        // all n-grams of the ASCII lower cased literal are required in the case folded bitmap
+    And(Vec<Prefilter>),
+    Or(Vec<Prefilter>),
+}
+
+// case sensitivity of literal matching, case-insensitive is ASCII only
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Case {
+    Sensitive,
+    Insensitive { folded_index: bool }, // folded_index: chunks have case folded trigram bitmaps
+}
+
+// every byte and bigram of literal is in the bitmaps
+fn has_short<T: ChunkBits + ?Sized>(chunk:&T, literal:&[u8]) -> bool {
+    let has = |bitmap:&[u64], h:usize| bitmap[h / 64] & (1u64 << (h % 64)) != 0;
+    literal.iter().all(|b| has(chunk.unigram(), *b as usize))
+        && literal.windows(2).all(|s| has(chunk.bigram(), hash_2_to_12(s[0], s[1]) as usize))
+}
+
+impl Prefilter {
+    // every literal is required
+    pub fn from_literals(patterns:&[&[u8]]) -> Prefilter {
+        let literals: Vec<Vec<u8>> = patterns.iter().filter(|p| !p.is_empty()).map(|p| p.to_vec()).collect();
+        if literals.is_empty() {
+            return Prefilter::All;
+        }
+        Prefilter::Literals(literals)
+    }
+
+    // every literal is required, ignoring ASCII case when case is Insensitive
+    pub fn from_literals_case(patterns:&[&[u8]], case:Case) -> Prefilter {
+        match case {
+            Case::Sensitive => Prefilter::from_literals(patterns),
+            Case::Insensitive { folded_index } => Prefilter::and(patterns.iter().map(|p| {
+                if folded_index && p.len() >= 3 {
+                    Prefilter::Folded(p.to_ascii_lowercase())
+                } else {
+                    // every case variant of the literal, as far as it stays small
+                    let hir = Hir::concat(p.iter().map(|b| Hir::class(Class::Bytes(case_class(*b)))).collect());
+                    Prefilter::from_regex(&hir)
+                }
+            }).collect()),
+        }
+    }
+
+    // required trigrams of a parsed regex
+    pub fn from_regex(hir:&Hir) -> Prefilter {
+        analyze(hir).into_prefilter()
+    }
+
+    pub fn and(list:Vec<Prefilter>) -> Prefilter {
+        let mut list: Vec<Prefilter> = list.into_iter().filter(|p| *p != Prefilter::All).collect();
+        match list.len() {
+            0 => Prefilter::All,
+            1 => list.pop().unwrap(),
+            _ => Prefilter::And(list),
+        }
+    }
+
+    pub fn or(list:Vec<Prefilter>) -> Prefilter {
+        if list.is_empty() || list.contains(&Prefilter::All) {
+            return Prefilter::All;
+        }
+        let mut list = list;
+        match list.len() {
+            1 => list.pop().unwrap(),
+            _ => Prefilter::Or(list),
+        }
+    }
+
+    pub fn is_all(&self) -> bool {
+        *self == Prefilter::All
+    }
+
+    // chunk may hold the strings, its bitmaps are hashed by ngrams
+    pub fn matches<T: ChunkBits + ?Sized>(&self, chunk:&T, ngrams:Ngrams) -> bool {
+        match self {
+            Prefilter::All => true,
+            Prefilter::Literals(literals) => literals.iter().all(|literal| {
+                if literal.len() >= ngrams.len {
+                    ngrams.contains(chunk.trigram(), literal)
+                } else {
+                    has_short(chunk, literal)
+                }
+            }),
+            Prefilter::Folded(literal) => chunk.folded().is_empty() || ngrams.contains(chunk.folded(), literal),
+            Prefilter::And(list) => list.iter().all(|p| p.matches(chunk, ngrams)),
+            Prefilter::Or(list) => list.iter().any(|p| p.matches(chunk, ngrams)),
+        }
+    }
+}
+
+// what is known about the strings a regex node matches:
+// either the exact set of strings, or a prefilter they all satisfy
+enum Info {
+    Exact(BTreeSet<Vec<u8>>),
+    Match(Prefilter),
+}
+
+impl Info {
+    fn into_prefilter(self) -> Prefilter {
+        match self {
+            Info::Exact(set) => {
+                if set.iter().any(|s| s.is_empty()) {
+                    return Prefilter::All;
+                }
+                Prefilter::or(set.iter().map(|s| Prefilter::from_literals(&[s])).collect())
+            },
+            Info::Match(p) => p,
+        }
+    }
+}
+
+fn analyze(hir:&Hir) -> Info {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => Info::Exact(BTreeSet::from([Vec::new()])),
+        HirKind::Literal(lit) => Info::Exact(BTreeSet::from([lit.0.to_vec()])),
+        HirKind::Class(class) => match class_strings(class) {
+            Some(set) => Info::Exact(set),
+            None => Info::Match(Prefilter::All),
+        },
+        HirKind::Capture(cap) => analyze(&cap.sub),
+        HirKind::Repetition(rep) => {
+            if rep.min == 0 {
+                Info::Match(Prefilter::All)
+            } else if rep.max == Some(rep.min) && rep.min <= 3 {
+                analyze_concat((0..rep.min).map(|_| rep.sub.as_ref()))
+            } else {
+                // at least one occurrence of sub is required
+                Info::Match(analyze(&rep.sub).into_prefilter())
+            }
+        },
+        HirKind::Concat(subs) => analyze_concat(subs.iter()),
+        HirKind::Alternation(subs) => {
+            let infos: Vec<Info> = subs.iter().map(analyze).collect();
+            if infos.iter().all(|i| matches!(i, Info::Exact(_))) {
+                let mut union = BTreeSet::new();
+                for info in infos.iter() {
+                    if let Info::Exact(set) = info { union.extend(set.iter().cloned()); }
+                }
+                if union.len() <= MAX_EXACT_SET {
+                    return Info::Exact(union);
+                }
+            }
+            Info::Match(Prefilter::or(infos.into_iter().map(Info::into_prefilter).collect()))
+        },
+    }
+}
+
+// concatenation keeps exact strings as a cross product while it is small,
+// otherwise the prefilters of each part are all required
+fn analyze_concat<'a>(subs:impl Iterator<Item=&'a Hir>) -> Info {
+    let mut required: Vec<Prefilter> = Vec::new();
+    let mut current: Option<BTreeSet<Vec<u8>>> = Some(BTreeSet::from([Vec::new()]));
+    for sub in subs {
+        match analyze(sub) {
+            Info::Exact(set) => {
+                let product = current.as_ref().map(|cur| cur.len() * set.len()).unwrap_or(0);
+                match current.take() {
+                    Some(cur) if product <= MAX_EXACT_SET => {
+                        current = Some(cur.iter().flat_map(|a| set.iter().map(move |b| [a.as_slice(), b.as_slice()].concat())).collect());
+                    },
+                    Some(cur) => {
+                        required.push(Info::Exact(cur).into_prefilter());
+                        current = Some(set);
+                    },
+                    None => { current = Some(set); },
+                }
+            },
+            Info::Match(p) => {
+                if let Some(cur) = current.take() {
+                    required.push(Info::Exact(cur).into_prefilter());
+                }
+                required.push(p);
+            },
+        }
+    }
+    if required.is_empty() {
+        return Info::Exact(current.unwrap_or_default());
+    }
+    if let Some(cur) = current {
+        required.push(Info::Exact(cur).into_prefilter());
+    }
+    Info::Match(Prefilter::and(required))
+}
+
+// byte class of both ASCII cases of b
+fn case_class(b:u8) -> ClassBytes {
+    let mut class = ClassBytes::new([ClassBytesRange::new(b, b)]);
+    class.push(ClassBytesRange::new(b.to_ascii_lowercase(), b.to_ascii_lowercase()));
+    class.push(ClassBytesRange::new(b.to_ascii_uppercase(), b.to_ascii_uppercase()));
+    class
+}
+
+// small character classes are expanded to their strings
+fn class_strings(class:&Class) -> Option<BTreeSet<Vec<u8>>> {
+    let mut set = BTreeSet::new();
+    match class {
+        Class::Unicode(cls) => {
+            for range in cls.ranges() {
+                for c in range.start()..=range.end() {
+                    if set.len() >= MAX_CLASS_SIZE { return None; }
+                    let mut buf = [0u8; 4];
+                    set.insert(c.encode_utf8(&mut buf).as_bytes().to_vec());
+                }
+            }
+        },
+        Class::Bytes(cls) => {
+            for range in cls.ranges() {
+                for b in range.start()..=range.end() {
+                    if set.len() >= MAX_CLASS_SIZE { return None; }
+                    set.insert(vec![b]);
+                }
+            }
+        },
+    }
+    Some(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Index;
+
+    fn prefilter(re:&str) -> Prefilter {
+        let hir = regex_syntax::ParserBuilder::new().utf8(false).build().parse(re).unwrap();
+        Prefilter::from_regex(&hir)
+    }
+
+    fn hash_of(text:&[u8]) -> Index {
+        let mut index = Index::new(Ngrams::default(), 0, 0, 0);
+        index.fill(Ngrams::default(), text);
+        index
+    }
+
+    #[test]
+    fn test_literal_regex() {
+        let p = prefilter(r"timeout after \d+ms");
+        assert!(!p.is_all());
+        assert!(p.matches(&hash_of(b"xx timeout after 100ms"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"xx timeout before 100ms"), Ngrams::default()));
+    }
+
+    #[test]
+    fn test_alternation() {
+        let p = prefilter("(disk|nvme) error");
+        assert!(p.matches(&hash_of(b"nvme error"), Ngrams::default()));
+        assert!(p.matches(&hash_of(b"disk error"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"ssd error"), Ngrams::default()));
+    }
+
+    #[test]
+    fn test_no_trigram() {
+        assert!(prefilter(r"\d+").is_all());
+        assert!(prefilter("(abc)?").is_all());
+        // bytes and bigrams still prune
+        assert!(!prefilter("a.b").is_all());
+        assert!(!prefilter("a.b").matches(&hash_of(b"xyz"), Ngrams::default()));
+    }
+
+    #[test]
+    fn test_case_insensitive_literals() {
+        let mut folded = Index::new_folded(Ngrams::default(), 0, 0, 0);
+        folded.fill(Ngrams::default(), b"an ERROR here");
+        let p = Prefilter::from_literals_case(&[b"Error"], Case::Insensitive { folded_index: true });
+        assert!(matches!(p, Prefilter::Folded(_)));
+        assert!(p.matches(&folded, Ngrams::default()));
+        // chunks without folded bitmap cannot be pruned
+        assert!(p.matches(&hash_of(b"nothing"), Ngrams::default()));
+        // without folded bitmap, case variants are used
+        let p = Prefilter::from_literals_case(&[b"Error"], Case::Insensitive { folded_index: false });
+        assert!(p.matches(&hash_of(b"an ERROR here"), Ngrams::default()));
+        assert!(p.matches(&hash_of(b"an eRrOr here"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"an warn here"), Ngrams::default()));
+        let p = Prefilter::from_literals_case(&[b"e"], Case::Insensitive { folded_index: true });
+        assert!(p.matches(&hash_of(b"E"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"x"), Ngrams::default()));
+    }
+
+    #[test]
+    fn test_short_literals() {
+        let p = Prefilter::from_literals(&[b"x"]);
+        assert!(p.matches(&hash_of(b"abcx"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"abc"), Ngrams::default()));
+        let p = Prefilter::from_literals(&[b"ab", b"error"]);
+        assert!(p.matches(&hash_of(b"ab error"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"a b error"), Ngrams::default()));
+        assert!(Prefilter::from_literals(&[b""]).is_all());
+    }
+
+    #[test]
+    fn test_ngram_length() {
+        let ngrams = Ngrams { len: 4, bits: 12, hash_fn: crate::header::HASH_MULTIPLY };
+        let mut index = Index::new_folded(ngrams, 0, 0, 0);
+        index.fill(ngrams, b"disk Error on sda");
+        assert_eq!(index.hash.len(), 4096 / 64);
+        assert!(Prefilter::from_literals(&[b"Error on"]).matches(&index, ngrams));
+        assert!(!Prefilter::from_literals(&[b"Error off"]).matches(&index, ngrams));
+        // shorter than an n-gram, pruned by bytes and bigrams
+        assert!(Prefilter::from_literals(&[b"sda"]).matches(&index, ngrams));
+        assert!(!Prefilter::from_literals(&[b"sdb"]).matches(&index, ngrams));
+        assert!(Prefilter::from_literals_case(&[b"ERROR ON"], Case::Insensitive { folded_index: true }).matches(&index, ngrams));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let p = prefilter("(?i)error");
+        assert!(!p.is_all());
+        assert!(p.matches(&hash_of(b"Error"), Ngrams::default()));
+        assert!(p.matches(&hash_of(b"ERROR"), Ngrams::default()));
+        assert!(!p.matches(&hash_of(b"warn"), Ngrams::default()));
+    }
+}
@@ -1,141 +1,829 @@
-use std::process;
+use std::io::{Write, ErrorKind};
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{Read, Write, self, Error};
-// file operation for search
-extern crate nix;
-#[allow(unused_imports)]
-use nix::fcntl;
-#[allow(unused_imports)]
-use nix::sys::stat;
-use nix::libc;
-use std::mem;
-use rkyv::Deserialize;
-
-use crate::index::{ListofIndex,hash_3_to_2};
-use lz4_flex::block::{decompress_into, get_maximum_output_size};
-
-
-// generate query vector
-fn fill_query(query_string:&String) -> Vec<u64> {
-    let mut bits:Vec::<bool> = vec![false;65536];
-    let query_bytes = query_string.as_bytes();
-    let iter = query_bytes.windows(3);
-
-    iter.for_each(|s| {
-        bits[hash_3_to_2(s[0], s[1], s[2]) as usize]=true;
-    });
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-    let mut bits_compact: Vec<u8> = Vec::new();
-
-    for i in 0..(65536/8) {
-        let mut u:u8 = 0;
-        for j in i*8..i*8+8 {
-            if bits[j] { u|=1; }
-            u <<= 1;
-        }
-        bits_compact.push(u);
-    };
-    log::debug!("bits_compact_len = {}, u64 size = {}", bits_compact.len(), bits_compact.len()/8);
-    log::debug!("bits_compact = {:x?}",bits_compact);
-
-    let mut u64_vec = Vec::new();
-    for chunk in bits_compact.chunks_exact(8) {
-        log::debug!("chunk = {:x?}",chunk);
-        let arr_ref: &[u8; 8] = &chunk.try_into().expect("Failed to convert slice into array");
-        let u64_val: u64 = unsafe { mem::transmute_copy(arr_ref) };
-        u64_vec.push(u64_val);
-    };
-
-    u64_vec
-
-}
-
-// check matching
-fn match_query(query:&Vec<u64>,index:&[u64]) -> bool {
-    log::debug!("len query={}, len index={}", query.len(), index.len());
-    for (q,i) in query.iter().zip(index.iter()) {
-        if *q==0 { continue; };
-        if (*q & *i)==*q { continue;};
-        return false;
-    };
-    true
-}
-
-// query
-pub fn query(file_fd:std::os::fd::RawFd, index: &mut File, query_string:&String,chunk_size:usize) -> std::io::Result<()> {
-    // read index
-    let mut index_buff_u8: Vec<u8> = Vec::new();
-    let mut read_buff: Vec<u8> = vec![0;chunk_size]; // source reading chunk buffer
-    let mut expand_buffer:Vec<u8> = vec![0;get_maximum_output_size(chunk_size)];
-    let read_to_end_ret =index.read_to_end(&mut index_buff_u8);
-    log::info!("read_to_end_ret: {:?}", read_to_end_ret);
-
-    let archived = unsafe { rkyv::archived_root::<ListofIndex>(&index_buff_u8[..]) };
-    log::debug!("unsafe rkyv finished");
-    let deserialized: ListofIndex = archived.deserialize(&mut rkyv::Infallible).unwrap();
-    log::debug!("deserialize len = {}", deserialized.n);
-
-    let query = fill_query(query_string);
-    log::debug!("fill_query = {:x?}",query);
-
-    let num_of_index = deserialized.n;
-    
-    let mut file_buf:Vec<u8> = vec![0;chunk_size];
-    let mut expand_buf:Vec<u8> = vec![0;get_maximum_output_size(chunk_size)];
-    let mut ith_index = 0;
-    for ielm in deserialized.indexies.iter() {
-        log::debug!("ielm offset = {}",ielm.offset);
-        log::debug!("ielm.hash = {:x?}",ielm.hash);
-        log::info!("query string = {}",&query_string);
-        log::debug!("query = {:x?}",query);
-        
-        if match_query(&query,&ielm.hash) {
-            log::info!("matched!");
-            let mut nread = 0;
-            let mut remain = ielm.compress_size as usize;
-            let mut rcount: usize = 0;
-            let mut read_pos:usize = 0;
-            let mut offset = ielm.offset as i64;
-
-            while remain>0 {
-                log::debug!("remain={}, offset={}, read_pos={}, nread={}",remain,offset,read_pos,nread);
-                unsafe {
-                    nread = libc::pread(file_fd,file_buf[read_pos..read_pos+remain].as_mut_ptr() as *mut libc::c_void,remain,offset);
-                };
-                match nread {
-                    -1 => {
-                        let err = nix::errno::errno();
-                        return Err(Error::from_raw_os_error(err))
-                    },
-                    0 => { break },
-                    _ => {
-                        remain -= nread as usize;
-                        rcount += nread as usize;
-                        offset += nread as i64;
-                        read_pos += nread as usize;
+use regex::bytes::{Regex, RegexBuilder};
+use crate::archive::ArchiveFile;
+use crate::error::{Error, Result};
+use crate::index::{expand_chunk, ArchivedIndex, MergedBits};
+use crate::verify::Checksums;
+use crate::glob::{lined_glob_all, glob_segments};
+use crate::prefilter::{Prefilter, Case};
+use crate::expr::{Expr, Scope};
+use crate::timestamp::{LineFilter, TimeWindow};
+use memchr::{memchr, memchr_iter, memmem};
+
+
+// a search is a prefilter pruning chunks by the index and a matcher verifying lines of candidate chunks
+pub struct Query {
+    pub prefilter: Prefilter,
+    pub matcher: Matcher,
+}
+
+pub enum Matcher {
+    Literal(Box<memmem::Finder<'static>>), // a plain query, or a glob with one literal part
+    Glob(Vec<Vec<u8>>), // literal patterns found in this order on one line
+    Regex(Regex),
+    Boolean(Expr, Scope),
+    Folded(Box<Matcher>), // ASCII case-insensitive, the inner matcher has lowercased patterns and sees a lowercased chunk
+}
+
+impl Query {
+    pub fn literal(query_string:&str, case:Case) -> Query {
+        Query::segments(vec![query_string.as_bytes()], case)
+    }
+
+    pub fn glob(glob:&str, case:Case) -> Result<Query> {
+        let segments = glob_segments(glob);
+        if segments.is_empty() {
+            return Err(Error::Query("glob pattern has no literal part".to_string()));
+        }
+        Ok(Query::segments(segments, case))
+    }
+
+    fn segments(segments:Vec<&[u8]>, case:Case) -> Query {
+        let prefilter = Prefilter::from_literals_case(&segments, case);
+        let segments: Vec<Vec<u8>> = match case {
+            Case::Sensitive => segments.iter().map(|s| s.to_vec()).collect(),
+            Case::Insensitive { .. } => segments.iter().map(|s| s.to_ascii_lowercase()).collect(),
+        };
+        let matcher = match &segments[..] {
+            [literal] => Matcher::Literal(Box::new(memmem::Finder::new(literal).into_owned())),
+            _ => Matcher::Glob(segments),
+        };
+        let matcher = match case {
+            Case::Sensitive => matcher,
+            Case::Insensitive { .. } => Matcher::Folded(Box::new(matcher)),
+        };
+        Query { prefilter, matcher }
+    }
+
+    // ^ and $ match at line boundaries, as lines are verified one by one
+    pub fn regex(pattern:&str, case:Case) -> Result<Query> {
+        let invalid = |e:String| Error::Query(e);
+        let insensitive = case != Case::Sensitive;
+        // the regex prefilter is built from the case-insensitive HIR, so it never needs the folded bitmaps
+        let hir = regex_syntax::ParserBuilder::new().utf8(false).multi_line(true).case_insensitive(insensitive).build()
+            .parse(pattern).map_err(|e| invalid(e.to_string()))?;
+        let regex = RegexBuilder::new(pattern).multi_line(true).case_insensitive(insensitive).build().map_err(|e| invalid(e.to_string()))?;
+        let prefilter = Prefilter::from_regex(&hir);
+        log::debug!("regex prefilter = {:?}", prefilter);
+        Ok(Query { prefilter, matcher: Matcher::Regex(regex) })
+    }
+
+    // with Scope::Line all terms have to be on the same line, with Scope::Chunk in the same chunk
+    pub fn boolean(expr:&str, scope:Scope, case:Case) -> Result<Query> {
+        let expr = Expr::parse(expr)?;
+        let prefilter = expr.prefilter(case);
+        log::debug!("expr = {:?}, prefilter = {:?}", expr, prefilter);
+        let matcher = match case {
+            Case::Sensitive => Matcher::Boolean(expr, scope),
+            Case::Insensitive { .. } => Matcher::Folded(Box::new(Matcher::Boolean(expr.to_ascii_lowercase(), scope))),
+        };
+        Ok(Query { prefilter, matcher })
+    }
+}
+
+// start and end of every line in chunk satisfying f
+fn lines_where(chunk:&[u8], eol:&[u8], f:impl Fn(&[u8]) -> bool) -> Vec<(usize,usize)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while start < chunk.len() {
+        let end = chunk[start..].iter().position(|x| eol.contains(x)).map_or(chunk.len(), |i| start+i+1);
+        if f(&chunk[start..end]) {
+            lines.push((start,end));
+        }
+        start = end;
+    }
+    lines
+}
+
+impl Matcher {
+    // start and end of every matching line in chunk, eol included
+    pub fn matched_lines(&self, chunk:&[u8], eol:&[u8]) -> Vec<(usize,usize)> {
+        match self {
+            Matcher::Literal(finder) => {
+                // a literal with an eol byte is on no line
+                if finder.needle().iter().any(|x| eol.contains(x)) {
+                    return Vec::new();
+                }
+                let mut lines = Vec::new();
+                let mut pos = 0;
+                while let Some(found) = finder.find(&chunk[pos..]) {
+                    let found = pos + found;
+                    let start = chunk[pos..found].iter().rposition(|x| eol.contains(x)).map_or(pos, |i| pos+i+1);
+                    let end = chunk[found..].iter().position(|x| eol.contains(x)).map_or(chunk.len(), |i| found+i+1);
+                    lines.push((start,end));
+                    pos = end;
+                    if pos >= chunk.len() { break };
+                }
+                lines
+            },
+            Matcher::Glob(segments) => {
+                let patterns: Vec<&[u8]> = segments.iter().map(|s| s.as_slice()).collect();
+                lined_glob_all(chunk, &patterns, eol)
+            },
+            Matcher::Regex(regex) => {
+                let mut lines = Vec::new();
+                let mut pos = 0;
+                while let Some(m) = regex.find_at(chunk, pos) {
+                    let start = chunk[..m.start()].iter().rposition(|x| eol.contains(x)).map_or(0, |i| i+1);
+                    let end = chunk[m.start()..].iter().position(|x| eol.contains(x)).map_or(chunk.len(), |i| m.start()+i+1);
+                    // a match running over the eol does not count for the line
+                    let content_end = if end>start && eol.contains(&chunk[end-1]) { end-1 } else { end };
+                    if m.end() <= content_end || regex.is_match(&chunk[start..content_end]) {
+                        lines.push((start,end));
                     }
+                    pos = end;
+                    if pos >= chunk.len() { break };
+                };
+                lines
+            },
+            Matcher::Boolean(expr, Scope::Line) => lines_where(chunk, eol, |line| expr.eval(line)),
+            Matcher::Boolean(expr, Scope::Chunk) => {
+                if !expr.eval(chunk) {
+                    return Vec::new();
+                }
+                // the chunk satisfies the query, show lines having any positive term
+                let terms = expr.positive_terms();
+                lines_where(chunk, eol, |line| terms.is_empty() || terms.iter().any(|t| memmem::find(line, t).is_some()))
+            },
+            // ASCII lowercasing keeps every byte in place, so lines found in the copy are lines of chunk
+            Matcher::Folded(matcher) => matcher.matched_lines(&chunk.to_ascii_lowercase(), eol),
+        }
+    }
+}
+
+// a matching line, or a whole candidate chunk when searching raw chunks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub archive: usize,   // position of the archive in the searcher
+    pub file: u32,        // position of the original file in the manifest of the archive
+    pub chunk: usize,     // position of the chunk in the archive
+    pub offset: u64,      // byte offset of the line in the original file
+    pub line_number: u64, // line number in the original file, from 1
+    pub line: Vec<u8>,    // the line with its line ending, the last line of a file may have none
+}
+
+// how hits are written, like grep -H -n -b
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OutputFormat {
+    pub archive_label: bool, // prefix lines with the archive name
+    pub with_filename: bool, // prefix lines with the name of the original file
+    pub line_number: bool,   // prefix lines with their line number in the original file
+    pub byte_offset: bool,   // prefix lines with their byte offset in the original file
+}
+
+// write the matched lines of a chunk after their prefix, the archive and file names
+// line numbers are counted only when they are written
+fn write_lines(out:&mut Vec<u8>, chunk:&[u8], candidate:&Candidate, lines:&[(usize, usize)], prefix:Option<&str>, format:OutputFormat) {
+    let (mut line_number, mut counted) = (candidate.first_line, 0);
+    for &(start, end) in lines {
+        if let Some(prefix) = prefix {
+            out.extend_from_slice(prefix.as_bytes());
+            out.push(b':');
+        }
+        if format.line_number {
+            line_number += memchr_iter(candidate.eol, &chunk[counted..start]).count() as u64;
+            counted = start;
+            let _ = write!(out, "{}:", line_number);
+        }
+        if format.byte_offset {
+            let _ = write!(out, "{}:", candidate.file_offset + start as u64);
+        }
+        out.extend_from_slice(&chunk[start..end]);
+        if chunk[start..end].last()!=Some(&candidate.eol) {
+            out.push(candidate.eol);
+        }
+    }
+}
+
+// a chunk as it is stored in the archive
+#[derive(Debug, Clone, Copy)]
+struct Stored {
+    chunk: usize,
+    offset: u64,
+    compress_size: usize,
+    raw: bool,
+    original_size: usize,
+    checksums: Option<Checksums>, // set when the archive is opened to check them
+}
+
+impl Stored {
+    fn of(chunk:usize, ielm:&ArchivedIndex, checksums:bool) -> Stored {
+        Stored {
+            chunk,
+            offset: ielm.offset,
+            compress_size: ielm.compress_size as usize,
+            raw: ielm.raw,
+            original_size: ielm.original_size as usize,
+            checksums: Some(Checksums::of(ielm)).filter(|_| checksums),
+        }
+    }
+
+    // read the chunk from data and decompress it into out, which has its original size
+    fn expand(&self, data:&File, file_buf:&mut Vec<u8>, out:&mut [u8]) -> Result<()> {
+        // both sizes are bounded by the chunk size, IndexFile::open_range rejects larger ones
+        file_buf.resize(self.compress_size, 0);
+        pread_exact(data, file_buf, self.offset)?;
+        if let Some(checksums) = self.checksums {
+            checksums.check_compressed(self.chunk, self.offset, file_buf)?;
+        }
+        expand_chunk(file_buf, self.raw, out)
+            .map_err(|message| Error::Decompress { chunk: self.chunk, offset: self.offset, message })?;
+        if let Some(checksums) = self.checksums {
+            checksums.check_original(self.chunk, self.offset, out)?;
+        }
+        Ok(())
+    }
+}
+
+// a chunk which passed the prefilter
+// a line running over the end of a chunk is verified and reported with the chunk it starts in
+#[derive(Debug, Clone)]
+struct Candidate<'a> {
+    archive: usize,
+    data: &'a File, // the archive file holding the chunk
+    stored: Stored,
+    continued: Vec<Stored>, // chunks the last line of a cut chunk goes on in, the last one holds its end
+    skip_head: bool,        // the chunk starts inside a line of the chunk before
+    eol: u8,
+    file: u32,
+    file_offset: u64,                // byte offset of the chunk in the original file
+    first_line: u64,                 // line number of the first line of the chunk in the original file
+    line_filter: Option<LineFilter>, // set when the chunk is partly outside the time window
+}
+
+// read exactly buf.len() bytes at offset
+fn pread_exact(data:&File, buf:&mut [u8], offset:u64) -> std::io::Result<()> {
+    data.read_exact_at(buf, offset).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => std::io::Error::new(ErrorKind::UnexpectedEof, "archive is shorter than its index"),
+        _ => e,
+    })
+}
+
+// lines of a decompressed candidate chunk verified by the matcher and in the time window
+fn matched_lines(chunk:&[u8], candidate:&Candidate, matcher:&Matcher) -> Vec<(usize, usize)> {
+    let lines = matcher.matched_lines(chunk, &[candidate.eol]);
+    match candidate.line_filter {
+        Some(line_filter) => line_filter.filter_lines(chunk, lines, candidate.eol),
+        None => lines,
+    }
+}
+
+// every matched line of a chunk is a hit
+// line numbers are counted from the chunk start through the matching lines
+fn chunk_hits(chunk:&[u8], candidate:&Candidate, lines:&[(usize, usize)]) -> Vec<Hit> {
+    let (mut line_number, mut counted) = (candidate.first_line, 0);
+    lines.iter().map(|&(start, end)| {
+        line_number += memchr_iter(candidate.eol, &chunk[counted..start]).count() as u64;
+        counted = start;
+        Hit {
+            archive: candidate.archive,
+            file: candidate.file,
+            chunk: candidate.stored.chunk,
+            offset: candidate.file_offset + start as u64,
+            line_number,
+            line: chunk[start..end].to_vec(),
+        }
+    }).collect()
+}
+
+// decompress a candidate chunk and collect its matched lines, or the whole chunk as one line
+fn search_chunk<T>(candidate:&Candidate, query:&Query, raw_chunks:bool, file_buf:&mut Vec<u8>, expand_buf:&mut Vec<u8>,
+        collect:&impl Fn(&[u8], &Candidate, &[(usize, usize)]) -> T) -> Result<T> {
+    let stored = candidate.stored;
+    expand_buf.resize(stored.original_size, 0);
+    stored.expand(candidate.data, file_buf, expand_buf)?;
+    if raw_chunks {
+        return Ok(collect(expand_buf, candidate, &[(0, expand_buf.len())]));
+    }
+    // the last line of a cut chunk is completed from the chunks it goes on in
+    for next in candidate.continued.iter() {
+        let end = expand_buf.len();
+        expand_buf.resize(end + next.original_size, 0);
+        next.expand(candidate.data, file_buf, &mut expand_buf[end..])?;
+        if let Some(eol) = memchr(candidate.eol, &expand_buf[end..]) {
+            expand_buf.truncate(end + eol + 1);
+            break;
+        }
+    }
+    let chunk = &expand_buf[..];
+    let mut lines = matched_lines(chunk, candidate, &query.matcher);
+    // the line the chunk starts in is reported by the chunk before
+    if candidate.skip_head {
+        let head = memchr(candidate.eol, &chunk[0..stored.original_size]).map_or(stored.original_size, |eol| eol + 1);
+        lines.retain(|&(start, _)| start >= head);
+    }
+    log::debug!("chunk {}: {} lines matched", stored.chunk, lines.len());
+    Ok(collect(chunk, candidate, &lines))
+}
+
+// verify a batch of candidate chunks on threads workers, collected chunks are returned in candidate order
+fn search_batch<T:Send>(batch:&[Candidate], query:&Query, raw_chunks:bool, threads:usize,
+        collect:&(impl Fn(&[u8], &Candidate, &[(usize, usize)]) -> T + Sync)) -> Result<Vec<T>> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<T>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(batch.len())).map(|_| scope.spawn(|| {
+            let mut file_buf:Vec<u8> = Vec::new();
+            let mut expand_buf:Vec<u8> = Vec::new();
+            let mut results = Vec::new();
+            // workers take the next unverified chunk until the batch is done
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let candidate = match batch.get(i) {
+                    Some(candidate) => candidate,
+                    None => break,
                 };
+                let result = search_chunk(candidate, query, raw_chunks, &mut file_buf, &mut expand_buf, collect);
+                let failed = result.is_err();
+                results.push((i, result));
+                if failed {
+                    next.store(batch.len(), Ordering::Relaxed);
+                }
+            }
+            results
+        })).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// searches a query over archives
+// candidate chunks are verified on threads workers in batches of max_inflight, hits come in archive order
+pub struct Searcher<'a> {
+    archives: Vec<&'a ArchiveFile>,
+    query: Query,
+    raw_chunks: bool,
+    threads: usize,
+    max_inflight: Option<usize>,
+    window: Option<TimeWindow>,
+}
+
+impl<'a> Searcher<'a> {
+    // all CPUs and 4 candidate chunks per thread in memory by default
+    pub fn new(query:Query) -> Searcher<'a> {
+        Searcher {
+            archives: Vec::new(),
+            query,
+            raw_chunks: false,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_inflight: None,
+            window: None,
+        }
+    }
+
+    pub fn archive(mut self, archive:&'a ArchiveFile) -> Searcher<'a> {
+        self.archives.push(archive);
+        self
+    }
+
+    pub fn archives(mut self, archives:impl IntoIterator<Item = &'a ArchiveFile>) -> Searcher<'a> {
+        self.archives.extend(archives);
+        self
+    }
+
+    // every candidate chunk is one hit as is, without verifying lines
+    pub fn raw_chunks(mut self, raw_chunks:bool) -> Searcher<'a> {
+        self.raw_chunks = raw_chunks;
+        self
+    }
+
+    pub fn threads(mut self, threads:usize) -> Searcher<'a> {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // candidate chunks held in memory at once
+    pub fn max_inflight(mut self, max_inflight:usize) -> Searcher<'a> {
+        self.max_inflight = Some(max_inflight.max(1));
+        self
+    }
+
+    // only chunks and lines with timestamps in window, every archive needs a time index
+    pub fn window(mut self, window:Option<TimeWindow>) -> Searcher<'a> {
+        self.window = window;
+        self
+    }
+
+    // chunks of every archive passing the time window and the prefilter
+    fn candidates(&self) -> Result<Vec<Candidate<'a>>> {
+        if self.query.prefilter.is_all() {
+            log::warn!("the query cannot be pruned by the index, every chunk is decompressed and scanned");
+        }
+        let mut candidates = Vec::new();
+        let mut chunks = 0;
+        for (id, archive) in self.archives.iter().enumerate() {
+            // ListofIndex is used in place without deserializing
+            let archived = archive.index.list();
+            log::debug!("index len = {}", archived.n);
+            let header = archive.header();
+            let eol = header.delimiter.unwrap_or(b'\n');
+            let time_format = header.time_format;
+            let ngrams = header.ngrams();
+            if self.window.is_some() && time_format.is_none() {
+                return Err(Error::InvalidInput(
+                    format!("{} has no timestamp index for --since and --until, create it with --timestamps", archive.name())));
+            }
+            let indexies = &archived.indexies;
+            // the line a cut chunk ends in goes on in the next chunk of its file
+            let goes_on = |i:usize| indexies[i].cut && indexies.get(i + 1).is_some_and(|next|
+                next.file==indexies[i].file && next.file_offset==indexies[i].file_offset + indexies[i].original_size as u64);
+            for (chunk, ielm) in indexies.iter().enumerate() {
+                log::debug!("ielm offset = {}",ielm.offset);
+                // the time range is cheaper to check than trigrams
+                let (min_time, max_time) = (ielm.min_time, ielm.max_time);
+                if self.window.is_some_and(|window| !window.overlaps(min_time, max_time)) {
+                    continue;
+                }
+                // a line running over the end of the chunk may match with the n-grams of the chunks it goes on in
+                let mut last = chunk;
+                while goes_on(last) && (last==chunk || indexies[last].lines==0) {
+                    last += 1;
+                }
+                let spanned = || MergedBits::of(&(chunk..=last).map(|i| &indexies[i]).collect::<Vec<_>>());
+                if self.query.prefilter.matches(ielm, ngrams) || (last > chunk && self.query.prefilter.matches(&spanned(), ngrams)) {
+                    // lines before the first timestamp of the chunk continue a record at min_time
+                    let line_filter = self.window.zip(time_format)
+                        .filter(|(window, _)| !window.covers(min_time, max_time))
+                        .map(|(window, format)| LineFilter { window, format, lead: Some(min_time).filter(|_| min_time <= max_time), latest: max_time });
+                    candidates.push(Candidate {
+                        archive: id,
+                        data: &archive.file,
+                        stored: Stored::of(chunk, ielm, archive.checksums),
+                        continued: (chunk + 1..=last).map(|i| Stored::of(i, &indexies[i], archive.checksums)).collect(),
+                        skip_head: chunk > 0 && goes_on(chunk - 1),
+                        eol,
+                        file: ielm.file,
+                        file_offset: ielm.file_offset,
+                        first_line: ielm.first_line,
+                        line_filter,
+                    });
+                }
             };
+            chunks += archived.n;
+        }
+        log::info!("{} of {} chunks in {} archives matched the index", candidates.len(), chunks, self.archives.len());
+        Ok(candidates)
+    }
+
+    fn batch(&self) -> usize {
+        self.max_inflight.unwrap_or(4 * self.threads)
+    }
+
+    // hits of every archive in archive order, an error ends them
+    pub fn hits(&self) -> Result<Hits<'_>> {
+        Ok(Hits { searcher: self, candidates: self.candidates()?, next: 0, ready: VecDeque::new(), failed: false })
+    }
 
-            match decompress_into(&file_buf[0..rcount], &mut expand_buf) {
-                Err(e) =>    { log::error!("an error at file:{} line:{} ,msg:{}",file!(),line!(), e); process::exit(1); },
-                _ => {
-                    // write string query code and output to STDOUT
-                    //target.write_all(&expand_buf[0..(ielm.original_size as usize)])?;
-                    io::stdout().write_all(&expand_buf)?;
+    // write hits like grep, returns the number of hits
+    // raw chunks are written as they are, without prefix
+    pub fn write_to(&self, out:&mut impl Write, format:OutputFormat) -> Result<usize> {
+        // line prefix of every file of every archive
+        let prefixes: Vec<Vec<Option<String>>> = self.archives.iter().map(|archive| {
+            archive.file_names().into_iter().map(|name| {
+                match (format.archive_label, format.with_filename) {
+                    (true, true) => Some(format!("{}:{}", archive.name(), name)),
+                    (true, false) => Some(archive.name().to_string()),
+                    (false, true) => Some(name),
+                    (false, false) => None,
+                }
+            }).collect()
+        }).collect();
+        // lines are written by the workers, without a Hit per line
+        let raw_chunks = self.raw_chunks;
+        let render = |chunk:&[u8], candidate:&Candidate, lines:&[(usize, usize)]| {
+            if raw_chunks {
+                return (1, chunk.to_vec());
+            }
+            let mut rendered = Vec::new();
+            let prefix = prefixes[candidate.archive][candidate.file as usize].as_deref();
+            write_lines(&mut rendered, chunk, candidate, lines, prefix, format);
+            (lines.len(), rendered)
+        };
+
+        let candidates = self.candidates()?;
+        let mut count = 0;
+        for batch in candidates.chunks(self.batch()) {
+            for (hits, rendered) in search_batch(batch, &self.query, self.raw_chunks, self.threads, &render)? {
+                out.write_all(&rendered)?;
+                count += hits;
+            }
+        }
+        log::info!("{} hits", count);
+        Ok(count)
+    }
+}
+
+// iterator over the hits of a search, candidate chunks are verified a batch at a time
+pub struct Hits<'s> {
+    searcher: &'s Searcher<'s>,
+    candidates: Vec<Candidate<'s>>,
+    next: usize, // first candidate of the next batch
+    ready: VecDeque<Hit>,
+    failed: bool,
+}
+
+impl Iterator for Hits<'_> {
+    type Item = Result<Hit>;
+
+    fn next(&mut self) -> Option<Result<Hit>> {
+        while self.ready.is_empty() {
+            if self.failed || self.next >= self.candidates.len() {
+                return None;
+            }
+            let end = (self.next + self.searcher.batch()).min(self.candidates.len());
+            let searcher = self.searcher;
+            match search_batch(&self.candidates[self.next..end], &searcher.query, searcher.raw_chunks, searcher.threads, &chunk_hits) {
+                Ok(hits) => self.ready.extend(hits.into_iter().flatten()),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
                 },
-            };
+            }
+            self.next = end;
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{source_of, temp_file};
+    use crate::index::{Index, IndexFile, Ngrams};
+    use crate::create_files::{create_files, Source};
+    use crate::header::Header;
+    use crate::timestamp::TimeFormat;
+    use std::io::Read;
+    use std::sync::OnceLock;
+
+    // candidate of a chunk given to the functions directly, its archive file is never read
+    fn candidate_at(first_line:u64, file_offset:u64) -> Candidate<'static> {
+        static DATA: OnceLock<File> = OnceLock::new();
+        let data = DATA.get_or_init(|| temp_file("candidate"));
+        let stored = Stored { chunk: 0, offset: 0, compress_size: 0, raw: false, original_size: 0, checksums: None };
+        Candidate { archive: 0, data, stored, continued: Vec::new(), skip_head: false, eol: b'\n', file: 0, file_offset, first_line, line_filter: None }
+    }
+
+    // hits of chunk written with format
+    fn render(chunk:&[u8], query:&Query, candidate:&Candidate, prefix:Option<&str>, format:OutputFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_lines(&mut out, chunk, candidate, &matched_lines(chunk, candidate, &query.matcher), prefix, format);
+        out
+    }
+
+    #[test]
+    fn test_write_hits() {
+        let chunk = b"foo error 1\nbar\nerror error 2\nlast error";
+        let out = render(chunk, &Query::literal("error", Case::Sensitive), &candidate_at(1, 0), None, OutputFormat::default());
+        assert_eq!(out, b"foo error 1\nerror error 2\nlast error\n");
+    }
+
+    #[test]
+    fn test_write_hits_none() {
+        assert!(matched_lines(b"abc\ndef\n", &candidate_at(1, 0), &Query::literal("cd", Case::Sensitive).matcher).is_empty());
+    }
+
+    #[test]
+    fn test_write_hits_offset() {
+        let format = OutputFormat { byte_offset: true, ..Default::default() };
+        let out = render(b"abc\nxyz abc\n", &Query::glob("x*c", Case::Sensitive).unwrap(), &candidate_at(1, 100), None, format);
+        assert_eq!(out, b"104:xyz abc\n");
+    }
+
+    #[test]
+    fn test_write_hits_number() {
+        let query = Query::literal("x", Case::Sensitive);
+        let chunk = b"a\nb x\nc\nd x\n";
+        let hits = chunk_hits(chunk, &candidate_at(10, 100), &matched_lines(chunk, &candidate_at(10, 100), &query.matcher));
+        assert_eq!(hits.iter().map(|hit| (hit.line_number, hit.offset, hit.line.as_slice())).collect::<Vec<_>>(),
+            vec![(11, 102, &b"b x\n"[..]), (13, 108, &b"d x\n"[..])]);
+        let format = OutputFormat { line_number: true, byte_offset: true, ..Default::default() };
+        let out = render(b"a\nb x\nc\nd x\n", &query, &candidate_at(10, 100), Some("a.log"), format);
+        assert_eq!(out, b"a.log:11:102:b x\na.log:13:108:d x\n");
+    }
+
+    #[test]
+    fn test_glob_prefilter() {
+        // "cd" spans the gap and must not be required
+        let query = Query::glob("abc*def", Case::Sensitive).unwrap();
+        let mut index = Index::new(Ngrams::default(), 0, 0, 0);
+        index.fill(Ngrams::default(), b"abc--def");
+        assert!(query.prefilter.matches(&index, Ngrams::default()));
+    }
+
+    #[test]
+    fn test_boolean_scope() {
+        let chunk = b"error disk\nnvme\nerror nvme debug\n";
+        let query = Query::boolean("error AND (disk OR nvme) AND NOT debug", Scope::Line, Case::Sensitive).unwrap();
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n"), vec![(0,11)]);
+        let query = Query::boolean("error AND nvme AND NOT sda", Scope::Chunk, Case::Sensitive).unwrap();
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n"), vec![(0,11),(11,16),(16,33)]);
+        let query = Query::boolean("error AND NOT debug", Scope::Chunk, Case::Sensitive).unwrap();
+        assert!(query.matcher.matched_lines(chunk, b"\n").is_empty());
+    }
+
+    #[test]
+    fn test_literal_lines() {
+        let chunk = b"disk error\nok\nerror error\nlast error";
+        let query = Query::literal("error", Case::Sensitive);
+        assert!(matches!(query.matcher, Matcher::Literal(_)));
+        let lines = vec![(0,11),(14,26),(26,36)];
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n"), lines);
+        // the same lines as the glob loop
+        let patterns: [&[u8]; 1] = [b"error"];
+        assert_eq!(lined_glob_all(chunk, &patterns, b"\n"), lines);
+        assert!(Query::literal("error\nok", Case::Sensitive).matcher.matched_lines(chunk, b"\n").is_empty());
+        assert!(matches!(Query::glob("disk*error", Case::Sensitive).unwrap().matcher, Matcher::Glob(_)));
+    }
+
+    #[test]
+    fn test_regex_lines() {
+        let query = Query::regex(r"timeout after \d+ms", Case::Sensitive).unwrap();
+        let chunk = b"timeout after 10ms\ntimeout after ms\nx timeout after 5ms y\n";
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n"), vec![(0,19),(36,58)]);
+        let query = Query::regex(r"^timeout", Case::Sensitive).unwrap();
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n").len(), 2);
+        // a match must not run over a line end
+        let query = Query::regex(r"10ms\stimeout", Case::Sensitive).unwrap();
+        assert!(query.matcher.matched_lines(chunk, b"\n").is_empty());
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let chunk = b"Disk ERROR
+error on nvme
+warn
+";
+        let insensitive = Case::Insensitive { folded_index: true };
+        let out = render(chunk, &Query::literal("Error", insensitive), &candidate_at(1, 0), None, OutputFormat::default());
+        assert_eq!(out, b"Disk ERROR\nerror on nvme\n");
+        let query = Query::boolean("error AND NOT DISK", Scope::Line, insensitive).unwrap();
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n"), vec![(11,25)]);
+        let query = Query::regex(r"^disk", insensitive).unwrap();
+        assert_eq!(query.matcher.matched_lines(chunk, b"\n"), vec![(0,11)]);
+
+        let mut index = Index::new_folded(Ngrams::default(), 0, 0, 0);
+        index.fill(Ngrams::default(), b"Disk ERROR");
+        assert!(Query::glob("disk*error", insensitive).unwrap().prefilter.matches(&index, Ngrams::default()));
+        assert!(!Query::literal("Error", Case::Sensitive).prefilter.matches(&index, Ngrams::default()));
+    }
+
+    // archive of sources named name
+    fn archive_of(name:&str, sources:&mut [Source], header:Header) -> ArchiveFile {
+        let mut file = temp_file(name);
+        create_files(sources, &mut file, None, header, 2).unwrap();
+        let index = IndexFile::open_embedded(&file).unwrap().unwrap();
+        ArchiveFile { name: name.to_string(), file, index, checksums: false }
+    }
+
+    #[test]
+    fn test_parallel_same_output() {
+        let mut text = Vec::new();
+        for i in 0..2000 {
+            text.extend_from_slice(format!("{} host{} level={}\n", i, i % 7, ["ERROR","info","warn"][i % 3]).as_bytes());
+        }
+        // two files, line 1000 starts the second one
+        let split = text.split_inclusive(|x| *x==b'\n').take(1000).map(|l| l.len()).sum::<usize>();
+        let mut sources = [source_of("parallel-a", &text[..split]), source_of("parallel-b", &text[split..])];
+        let archive = archive_of("parallel-target", &mut sources, Header::new(1024));
+        assert!(archive.index.list().n > 8);
+
+        let search = |threads, max_inflight| {
+            let query = Query::regex(r"host3 level=ERROR", Case::Sensitive).unwrap();
+            let searcher = Searcher::new(query).archive(&archive).threads(threads).max_inflight(max_inflight);
+            let mut out = Vec::new();
+            let format = OutputFormat { byte_offset: true, with_filename: true, ..Default::default() };
+            assert_eq!(searcher.write_to(&mut out, format).unwrap(), 2000 / 21 + 1);
+            out
         };
-        
+        let serial = search(1, 1);
+        assert_eq!(search(4, 2), serial);
+        assert_eq!(search(8, 32), serial);
+
+        // byte offsets are within the original file
+        let offset_of = |line:&str| memmem::find(&text, line.as_bytes()).unwrap();
+        let lines: Vec<&[u8]> = serial.split(|x| *x==b'\n').collect();
+        assert_eq!(lines[0], format!("parallel-a.log:{}:3 host3 level=ERROR", offset_of("\n3 host3") + 1).as_bytes());
+        let second = lines.iter().position(|l| l.starts_with(b"parallel-b.log:")).unwrap();
+        assert_eq!(lines[second], format!("parallel-b.log:{}:1011 host3 level=ERROR", offset_of("\n1011 host3") + 1 - split).as_bytes());
 
-        ith_index+=1;
-        log::info!("ith index match = {}",ith_index);
-        if ith_index >= num_of_index {     
-            log::info!("ielm loop finished");   
-            break 
+        // typed hits locate the same lines
+        let searcher = Searcher::new(Query::literal("1011 host3", Case::Sensitive)).archive(&archive).threads(3);
+        let hits = searcher.hits().unwrap().collect::<Result<Vec<Hit>>>().unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].file, hits[0].line_number, hits[0].offset), (1, 12, (offset_of("\n1011 host3") + 1 - split) as u64));
+        assert_eq!(hits[0].line, b"1011 host3 level=ERROR\n");
+    }
+
+    #[test]
+    fn test_match_across_cut() {
+        let text: Vec<u8> = (0..40u64).flat_map(|i| format!("{:02} {:016x}\n", i, (i + 1).wrapping_mul(0x9e3779b97f4a7c15)).into_bytes()).collect();
+        let archive = archive_of("cut.mms", &mut [source_of("cut", &text)], Header { delimiter: None, ..Header::new(64) });
+        let cut = archive.index.list().indexies[1].file_offset as usize;
+        assert!(archive.index.list().indexies[0].cut);
+        let (line_start, line_end) = (cut - cut % 20, cut - cut % 20 + 20);
+        let hits = |query:&[u8]| {
+            assert_eq!(memmem::find_iter(&text, query).count(), 1);
+            let searcher = Searcher::new(Query::literal(std::str::from_utf8(query).unwrap(), Case::Sensitive)).archive(&archive);
+            searcher.hits().unwrap().collect::<Result<Vec<Hit>>>().unwrap()
         };
-    };
-    log::info!("search() finished");
+        // a line cut by the chunk size is verified whole and reported once, with the chunk it starts in
+        for query in [&text[cut - 2..cut + 8], &text[cut - 2..cut + 2], &text[cut - 4..cut], &text[cut..cut + 8], &text[line_start..line_end - 1]] {
+            let hits = hits(query);
+            assert_eq!(hits.len(), 1);
+            assert_eq!((hits[0].chunk, hits[0].offset, hits[0].line_number), (0, line_start as u64, line_start as u64 / 20 + 1));
+            assert_eq!(hits[0].line, &text[line_start..line_end]);
+        }
+        // lines after the cut line are the next chunk's
+        let hits = hits(&text[line_end..line_end + 10]);
+        assert_eq!((hits.len(), hits[0].chunk, hits[0].offset), (1, 1, line_end as u64));
+    }
+
+    #[test]
+    fn test_federated() {
+        let host1 = archive_of("host1.mms", &mut [source_of("host1", &b"disk error on sda\nok\n".repeat(10))], Header::new(64));
+        let host2 = archive_of("host2.mms", &mut [source_of("host2", &b"ok\nerror on nvme0\n".repeat(10))], Header::new(64));
+        let searcher = Searcher::new(Query::literal("error", Case::Sensitive)).archives([&host1, &host2]).threads(4).max_inflight(3);
+        let mut out = Vec::new();
+        searcher.write_to(&mut out, OutputFormat { archive_label: true, ..Default::default() }).unwrap();
+        let expected = [b"host1.mms:disk error on sda\n".repeat(10), b"host2.mms:error on nvme0\n".repeat(10)].concat();
+        assert_eq!(String::from_utf8(out).unwrap(), String::from_utf8(expected).unwrap());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let mut text = Vec::new();
+        for i in 0..200 {
+            text.extend_from_slice(format!("2023-10-11T10:{:02}:{:02}Z error {}\n  at frame {}\n", i / 60, i % 60, i, i).as_bytes());
+        }
+        let header = Header { time_format: Some(TimeFormat::Rfc3339), ..Header::new(256) };
+        let archive = archive_of("window", &mut [source_of("window", &text)], header);
+        let chunks = archive.index.list().indexies.iter().filter(|ielm| ielm.min_time <= ielm.max_time).count();
+        assert_eq!(chunks, archive.index.list().n as usize);
 
-    Ok(())
-}
\ No newline at end of file
+        let since = 1697018400 + 65; // 10:01:05
+        let window = Some(TimeWindow::new(Some(since), Some(since + 10)));
+        let searcher = Searcher::new(Query::literal("at frame", Case::Sensitive)).archive(&archive).threads(2).max_inflight(4).window(window);
+        let mut out = Vec::new();
+        searcher.write_to(&mut out, OutputFormat::default()).unwrap();
+        let expected: String = (65..75).map(|i| format!("  at frame {}\n", i)).collect();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let plain = archive_of("window-plain", &mut [source_of("window-plain", &text)], Header::new(256));
+        let searcher = Searcher::new(Query::literal("error", Case::Sensitive)).archive(&plain).window(window);
+        assert!(matches!(searcher.hits(), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_corrupt_chunk() {
+        let archive = archive_of("corrupt", &mut [source_of("corrupt", &b"disk error on sda\nok\n".repeat(20))], Header::new(64));
+        let ielm = &archive.index.list().indexies[3];
+        let (offset, size) = (ielm.offset, ielm.compress_size as usize);
+        archive.file.write_all_at(&vec![0xff; size], offset).unwrap();
+
+        // a bad chunk ends the search with an error, the process goes on
+        let searcher = Searcher::new(Query::literal("error", Case::Sensitive)).archive(&archive).threads(2);
+        let err = searcher.write_to(&mut Vec::new(), OutputFormat::default()).unwrap_err();
+        assert!(matches!(err, Error::Decompress { chunk: 3, offset: o, .. } if o==offset));
+        let mut reader = archive.reader().unwrap();
+        let err = Error::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+        assert!(matches!(err, Error::Decompress { chunk: 3, .. }));
+    }
+
+    #[test]
+    fn test_incompressible_chunks() {
+        // random lines do not shrink by compression and are stored raw
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut text = Vec::new();
+        for i in 0..400 {
+            let line: Vec<u8> = (0..60).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b'!' + (state % 90) as u8
+            }).collect();
+            text.extend_from_slice(&line);
+            text.extend_from_slice(if i==300 { b" needle\n" } else { b"\n" });
+        }
+        text.extend_from_slice(&b"disk error on sda\n".repeat(200));
+        let archive = archive_of("incompressible", &mut [source_of("incompressible", &text)], Header::new(1024)).verify_checksums(true);
+        let chunks = &archive.index.list().indexies;
+        assert!(chunks.iter().any(|ielm| ielm.raw && ielm.compress_size==ielm.original_size));
+        assert!(chunks.iter().any(|ielm| !ielm.raw && ielm.compress_size < ielm.original_size));
+
+        let hits = |query| Searcher::new(Query::literal(query, Case::Sensitive)).archive(&archive).hits().unwrap().count();
+        assert_eq!(hits("needle"), 1);
+        assert_eq!(hits("disk error"), 200);
+        let mut expanded = Vec::new();
+        archive.reader().unwrap().read_to_end(&mut expanded).unwrap();
+        assert_eq!(expanded, text);
+        assert!(archive.verify().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, ErrorKind};
+use std::os::unix::fs::FileExt;
+use crate::error::{Error, Result};
+use crate::index::{expand_chunk, IndexFile};
+use crate::verify::Checksums;
+
+// decompressed chunks kept by default, enough for sequential reads and small seeks back
+const DEFAULT_CACHE: usize = 4;
+
+// where a chunk is in the archive and in the original bytes
+#[derive(Debug, Clone, Copy)]
+struct ChunkPos {
+    offset: u64,
+    compress_size: usize,
+    raw: bool,
+    original_size: usize,
+    start: u64, // original byte offset of the chunk, files of the manifest are concatenated
+    checksums: Checksums,
+}
+
+// the original bytes of an archive, read and seeked like the original file
+// only the chunks touched are read and decompressed, the last ones are cached
+pub struct ArchiveReader {
+    archive: File,
+    chunks: Vec<ChunkPos>,
+    len: u64,
+    pos: u64,
+    cache: VecDeque<(usize, Vec<u8>)>, // most recently used first
+    cache_size: usize,
+    compressed: Vec<u8>,
+    verify: bool, // check chunks against their checksums
+}
+
+impl ArchiveReader {
+    // the chunk positions are copied, index_file is not needed afterwards
+    pub fn new(archive:File, index_file:&IndexFile) -> ArchiveReader {
+        let mut start = 0;
+        let chunks = index_file.list().indexies.iter().map(|ielm| {
+            let chunk = ChunkPos { offset: ielm.offset, compress_size: ielm.compress_size as usize, raw: ielm.raw, original_size: ielm.original_size as usize, start, checksums: Checksums::of(ielm) };
+            start += ielm.original_size as u64;
+            chunk
+        }).collect();
+        ArchiveReader { archive, chunks, len: start, pos: 0, cache: VecDeque::new(), cache_size: DEFAULT_CACHE, compressed: Vec::new(), verify: false }
+    }
+
+    // keep at most cache_size decompressed chunks in memory, at least one
+    pub fn with_cache(mut self, cache_size:usize) -> ArchiveReader {
+        self.cache_size = cache_size.max(1);
+        self.cache.truncate(self.cache_size);
+        self
+    }
+
+    // check every chunk read against its checksums
+    pub fn with_checksums(mut self, verify:bool) -> ArchiveReader {
+        self.verify = verify;
+        self
+    }
+
+    // decompressed bytes of chunk i, from the cache or the archive
+    fn chunk(&mut self, i:usize) -> Result<&[u8]> {
+        if let Some(hit) = self.cache.iter().position(|(id, _)| *id==i) {
+            let entry = self.cache.remove(hit).unwrap();
+            self.cache.push_front(entry);
+        } else {
+            let pos = self.chunks[i];
+            self.compressed.resize(pos.compress_size, 0);
+            self.archive.read_exact_at(&mut self.compressed, pos.offset)?;
+            if self.verify {
+                pos.checksums.check_compressed(i, pos.offset, &self.compressed)?;
+            }
+            // reuse the buffer of the least recently used chunk
+            let mut data = if self.cache.len() >= self.cache_size { self.cache.pop_back().unwrap().1 } else { Vec::new() };
+            data.resize(pos.original_size, 0);
+            let size = expand_chunk(&self.compressed, pos.raw, &mut data)
+                .map_err(|message| Error::Decompress { chunk: i, offset: pos.offset, message })?;
+            if size != pos.original_size {
+                return Err(Error::Decompress { chunk: i, offset: pos.offset, message: format!("{} bytes expanded, index says {}", size, pos.original_size) });
+            }
+            if self.verify {
+                pos.checksums.check_original(i, pos.offset, &data)?;
+            }
+            self.cache.push_front((i, data));
+        }
+        Ok(&self.cache[0].1)
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf:&mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        // the last chunk starting at or before pos holds it, empty chunks are skipped over
+        let i = self.chunks.partition_point(|chunk| chunk.start <= self.pos) - 1;
+        let skip = (self.pos - self.chunks[i].start) as usize;
+        let chunk = self.chunk(i)?;
+        let n = buf.len().min(chunk.len() - skip);
+        buf[0..n].copy_from_slice(&chunk[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ArchiveReader {
+    fn seek(&mut self, pos:SeekFrom) -> std::io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::End(d) => (self.len, d),
+            SeekFrom::Current(d) => (self.pos, d),
+        };
+        self.pos = base.checked_add_signed(delta)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{source_of, temp_file};
+    use crate::create_files::create_files;
+    use crate::header::Header;
+
+    #[test]
+    fn test_read_seek() {
+        let text: Vec<u8> = (0..3000).flat_map(|i| format!("{} host{} value={}\n", i, i % 7, i * 31 % 1000).into_bytes()).collect();
+        let split = 20000;
+        let mut target = temp_file("reader-target");
+        create_files(&mut [source_of("reader-a", &text[..split]), source_of("reader-b", &text[split..])], &mut target, None, Header::new(1024), 2).unwrap();
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        let mut reader = ArchiveReader::new(target.try_clone().unwrap(), &index_file).with_cache(2);
+
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, text);
+
+        // reads across chunk and file boundaries
+        for start in [0, 1000, 1023, split - 5, text.len() - 10] {
+            assert_eq!(reader.seek(SeekFrom::Start(start as u64)).unwrap(), start as u64);
+            let mut buf = vec![0u8; 3000];
+            let n = (text.len() - start).min(buf.len());
+            reader.read_exact(&mut buf[0..n]).unwrap();
+            assert_eq!(&buf[0..n], &text[start..start + n]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), text.len() as u64 - 4);
+        reader.seek(SeekFrom::Current(-6)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &text[text.len() - 10..]);
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-(text.len() as i64) - 1)).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use lz4_flex::block::get_maximum_output_size;
+use memchr::memmem;
+use crate::create_files::{archived_size, create_segments, index_chunk, push_chunk, write_index, Chunking, Segment};
+use crate::error::{Error, Result};
+use crate::header::{ChunkFrame, Header, Footer, FOOTER_SIZE, FRAME_MAGIC, FRAME_SIZE, HEADER_SIZE};
+use crate::index::{checksum, expand_chunk, FileEntry, ListofIndex};
+
+// what repair and reindex found in an archive
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Repaired {
+    pub chunks: usize,  // chunks indexed again, or written by a rechunk
+    pub skipped: usize, // damaged chunks left out of the index, their lines are lost
+    pub truncated: u64, // bytes cut off after the last chunk, a torn chunk or the old embedded index
+}
+
+// the frame at pos with the name of its file, None when there is no complete frame
+pub(crate) fn read_frame(archive:&File, pos:u64, len:u64) -> Result<Option<ChunkFrame>> {
+    if pos + FRAME_SIZE as u64 > len {
+        return Ok(None);
+    }
+    let mut bytes = [0u8; FRAME_SIZE];
+    archive.read_exact_at(&mut bytes, pos)?;
+    let (mut frame, name_len) = match ChunkFrame::from_bytes(&bytes) {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    if pos + (FRAME_SIZE + name_len) as u64 > len {
+        return Ok(None);
+    }
+    let mut name = vec![0u8; name_len];
+    archive.read_exact_at(&mut name, pos + FRAME_SIZE as u64)?;
+    frame.name = String::from_utf8_lossy(&name).into_owned();
+    Ok(Some(frame))
+}
+
+// header at the start of an archive, an archive of an older framed format is taken as the current one
+fn read_header(archive:&File) -> Result<Header> {
+    let mut head = [0u8; HEADER_SIZE];
+    archive.read_exact_at(&mut head, 0).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::CorruptIndex("archive is too short to have a header".to_string()),
+        _ => e.into(),
+    })?;
+    Header::from_bytes_upgrade(&head)
+}
+
+// a complete chunk found by its frame, its compressed bytes start at offset
+struct Located {
+    frame: ChunkFrame,
+    offset: u64,
+}
+
+impl Located {
+    // original bytes, None when they do not match the frame
+    fn expand(&self, archive:&File) -> Result<Option<Vec<u8>>> {
+        let mut compressed = vec![0u8; self.frame.compress_size as usize];
+        archive.read_exact_at(&mut compressed, self.offset)?;
+        let mut data = vec![0u8; self.frame.original_size as usize];
+        Ok(match expand_chunk(&compressed, self.frame.raw(), &mut data) {
+            Ok(size) if size==data.len() && checksum(&data)==self.frame.original_checksum => Some(data),
+            _ => None,
+        })
+    }
+}
+
+// a complete chunk at pos whose compressed bytes match their checksum
+fn read_chunk(archive:&File, pos:u64, len:u64, header:Header) -> Result<Option<Located>> {
+    let frame = match read_frame(archive, pos, len)? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    let offset = pos + frame.size() as u64;
+    if frame.original_size > header.chunk_size || frame.compress_size as usize > get_maximum_output_size(header.chunk_size as usize)
+        || offset + frame.compress_size as u64 > len {
+        return Ok(None);
+    }
+    let mut compressed = vec![0u8; frame.compress_size as usize];
+    archive.read_exact_at(&mut compressed, offset)?;
+    if checksum(&compressed) != frame.compressed_checksum {
+        return Ok(None);
+    }
+    Ok(Some(Located { frame, offset }))
+}
+
+// bytes scanned at once for the magic of the next frame
+const SCAN_WINDOW: usize = 1 << 20;
+
+// position of the next complete chunk after start, skipping damaged bytes, scanned window bytes at a time
+fn next_chunk(archive:&File, start:u64, len:u64, header:Header, window:usize) -> Result<Option<u64>> {
+    let mut buf = vec![0u8; window];
+    let mut pos = start;
+    while pos < len {
+        let n = buf.len().min((len - pos) as usize);
+        archive.read_exact_at(&mut buf[0..n], pos)?;
+        for found in memmem::find_iter(&buf[0..n], &FRAME_MAGIC) {
+            if pos + found as u64 != start && read_chunk(archive, pos + found as u64, len, header)?.is_some() {
+                return Ok(Some(pos + found as u64));
+            }
+        }
+        // a magic cut at the end of buf is found in the next round
+        if n < buf.len() {
+            break;
+        }
+        pos += (n - FRAME_MAGIC.len() + 1) as u64;
+    }
+    Ok(None)
+}
+
+// whether the bytes from pos to next are an index embedded before an append, which leaves it between the chunks
+// its footer ends them and points at it, after the padding following the chunk ending at pos
+fn old_index(archive:&File, pos:u64, next:u64) -> Result<bool> {
+    if next - pos < FOOTER_SIZE as u64 {
+        return Ok(false);
+    }
+    let mut bytes = [0u8; FOOTER_SIZE];
+    archive.read_exact_at(&mut bytes, next - FOOTER_SIZE as u64)?;
+    Ok(Footer::from_bytes(&bytes).is_some_and(|footer| footer.index_offset >= pos && footer.index_offset < pos + 16
+        && footer.index_offset.checked_add(footer.index_len) == Some(next - FOOTER_SIZE as u64)))
+}
+
+// every complete chunk of the archive in archive order, found through the frames from its header on
+// a damaged chunk is skipped up to the next complete frame
+// returns them with the end of the last one
+fn locate_chunks(archive:&File, header:Header, repaired:&mut Repaired) -> Result<(Vec<Located>, u64)> {
+    let len = archive.metadata()?.len();
+    let mut chunks = Vec::new();
+    let mut pos = HEADER_SIZE as u64;
+    let mut end = pos;
+    loop {
+        match read_chunk(archive, pos, len, header)? {
+            Some(located) => {
+                pos = located.offset + located.frame.compress_size as u64;
+                end = pos;
+                chunks.push(located);
+            },
+            None => match next_chunk(archive, pos, len, header, SCAN_WINDOW)? {
+                Some(next) if old_index(archive, pos, next)? => pos = next,
+                Some(next) => {
+                    log::warn!("{} damaged bytes at offset {} skipped", next - pos, pos);
+                    repaired.skipped += 1;
+                    pos = next;
+                },
+                None => break,
+            },
+        }
+    }
+    repaired.truncated = len - end;
+    Ok((chunks, end))
+}
+
+// manifest of the files of chunks, names and modification times are taken from their frames
+fn manifest_of(chunks:&[Located]) -> Vec<FileEntry> {
+    let mut files: Vec<FileEntry> = Vec::new();
+    for Located { frame, .. } in chunks {
+        while files.len() <= frame.file as usize {
+            files.push(FileEntry { name: String::new(), size: 0, mtime: 0 });
+        }
+        let entry = &mut files[frame.file as usize];
+        if entry.name.is_empty() {
+            entry.name = frame.name.clone();
+        }
+        entry.mtime = entry.mtime.max(frame.mtime);
+    }
+    for (id, entry) in files.iter_mut().enumerate() {
+        if entry.name.is_empty() {
+            log::warn!("file {} of the manifest has no chunk left, its name is lost", id);
+            entry.name = format!("file-{}", id);
+        }
+    }
+    files
+}
+
+// index the chunks of target in place with the settings of header and write their index
+// chunks are decompressed one after another, a chunk not decompressing to its original bytes is skipped
+// target is truncated after the last chunk and starts with header afterwards
+fn index_chunks(target:&mut File, index:Option<&mut File>, chunks:Vec<Located>, end:u64, header:Header, repaired:&mut Repaired) -> Result<()> {
+    let chunking = Chunking::new(header, 1, true);
+    let overlap = header.ngrams().overlap();
+    let mut indexies = ListofIndex { n: 0, files: manifest_of(&chunks), indexies: Vec::new() };
+
+    // the next chunk which decompresses, from chunks[*next] on
+    let archive = &*target;
+    let mut next = 0;
+    let mut expand_next = |next:&mut usize| -> Result<Option<(usize, Vec<u8>)>> {
+        while let Some(located) = chunks.get(*next) {
+            *next += 1;
+            match located.expand(archive)? {
+                Some(data) => return Ok(Some((*next - 1, data))),
+                None => {
+                    log::warn!("chunk at offset {} does not decompress to its original bytes, skipped", located.offset);
+                    repaired.skipped += 1;
+                },
+            }
+        }
+        Ok(None)
+    };
+    // n-grams across a cut inside a record are hashed into both chunks, as create does
+    let cut_inside = |a:&(usize, Vec<u8>), b:&(usize, Vec<u8>)| {
+        let (fa, fb) = (&chunks[a.0].frame, &chunks[b.0].frame);
+        fa.file==fb.file && fa.file_offset + a.1.len() as u64==fb.file_offset
+            && header.delimiter.is_none_or(|d| a.1.last()!=Some(&d))
+    };
+    let mut before: Option<(usize, Vec<u8>)> = None;
+    let mut current = expand_next(&mut next)?;
+    while let Some(chunk) = current {
+        let after = expand_next(&mut next)?;
+        let prev_tail = match &before {
+            Some(before) if cut_inside(before, &chunk) => before.1[before.1.len().saturating_sub(overlap)..].to_vec(),
+            _ => Vec::new(),
+        };
+        let mut data = chunk.1.clone();
+        let lookahead = match &after {
+            Some(after) if cut_inside(&chunk, after) => {
+                let head = &after.1[0..overlap.min(after.1.len())];
+                data.extend_from_slice(head);
+                head.len()
+            },
+            _ => 0,
+        };
+        let Located { frame, offset } = &chunks[chunk.0];
+        let (mut ielm, continued) = index_chunk(&data, lookahead, prev_tail, frame.file, frame.file_offset, frame.mtime, chunking);
+        ielm.offset = *offset;
+        ielm.compress_size = frame.compress_size;
+        ielm.raw = frame.raw();
+        ielm.compressed_checksum = frame.compressed_checksum;
+        push_chunk(&mut indexies, ielm, continued);
+        repaired.chunks += 1;
+        before = Some(chunk);
+        current = after;
+    }
+    for id in 0..indexies.files.len() {
+        indexies.files[id].size = archived_size(&indexies, id as u32);
+    }
+    log::info!("{} chunks indexed, {} skipped, {} bytes after the last chunk", repaired.chunks, repaired.skipped, repaired.truncated);
+
+    target.write_all_at(&header.to_bytes(), 0)?;
+    target.set_len(end)?;
+    target.seek(SeekFrom::Start(end))?;
+    write_index(target, index, header, &indexies, end)
+}
+
+// scan the chunks of an archive from its header and write their index again
+// chunks are found through their frames, a damaged chunk is skipped up to the next complete frame
+// and the archive is truncated after the last complete chunk, dropping a torn chunk or the old index
+// when index is None, the index is embedded into target as in a single-file archive
+// target is written in place, ArchiveWriter::repair repairs a single-file archive on a copy
+pub fn repair_archive(target:&mut File, index:Option<&mut File>) -> Result<Repaired> {
+    let header = read_header(target)?;
+    let mut repaired = Repaired::default();
+    let (chunks, end) = locate_chunks(target, header, &mut repaired)?;
+    index_chunks(target, index, chunks, end, header, &mut repaired)?;
+    Ok(repaired)
+}
+
+// index the chunks of an archive again with the n-grams, hash width, case folding and timestamps of settings
+// chunks are kept as they are, so they keep their chunk size and delimiter
+// it works as repair_archive and upgrades an archive of an older framed format to the current one
+pub fn reindex_archive(target:&mut File, index:Option<&mut File>, settings:Header) -> Result<Repaired> {
+    let header = read_header(target)?;
+    let mut repaired = Repaired::default();
+    let (chunks, end) = locate_chunks(target, header, &mut repaired)?;
+    let header = Header { chunk_size: header.chunk_size, delimiter: header.delimiter, ..settings };
+    index_chunks(target, index, chunks, end, header, &mut repaired)?;
+    Ok(repaired)
+}
+
+// original bytes of one file of an archive, read chunk after chunk
+struct ChunkSource<'a> {
+    archive: &'a File,
+    chunks: Vec<Located>,
+    next: usize,
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkSource<'_> {
+    fn read(&mut self, buf:&mut [u8]) -> std::io::Result<usize> {
+        while self.pos==self.data.len() {
+            let Some(located) = self.chunks.get(self.next) else { return Ok(0) };
+            self.data = located.expand(self.archive)?.ok_or_else(|| Error::Decompress {
+                chunk: self.next,
+                offset: located.offset,
+                message: "chunk does not decompress to its original bytes, repair the archive first".to_string(),
+            })?;
+            self.pos = 0;
+            self.next += 1;
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[0..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// archive the original bytes of archive again into the empty target with the settings of header
+// chunks are cut anew with its chunk size and delimiter, the files of the manifest are kept
+pub fn rechunk_archive(archive:&File, target:&mut File, index:Option<&mut File>, header:Header, threads:usize) -> Result<Repaired> {
+    let mut repaired = Repaired::default();
+    let (chunks, _) = locate_chunks(archive, read_header(archive)?, &mut repaired)?;
+    repaired.truncated = 0;
+    let files = manifest_of(&chunks);
+    let mut sources: Vec<ChunkSource> = (0..files.len())
+        .map(|_| ChunkSource { archive, chunks: Vec::new(), next: 0, data: Vec::new(), pos: 0 })
+        .collect();
+    for located in chunks {
+        let source = &mut sources[located.frame.file as usize];
+        let position = source.chunks.last().map_or(0, |last| last.frame.file_offset + last.frame.original_size as u64);
+        if located.frame.file_offset != position {
+            log::warn!("bytes {}..{} of {} are lost", position, located.frame.file_offset, located.frame.name);
+        }
+        source.chunks.push(located);
+    }
+    // a new chunk can hold bytes of several old ones, its lines are read with the last modification time of the file
+    let segments: Vec<Segment> = sources.iter_mut().zip(&files).enumerate()
+        .map(|(id, (source, entry))| Segment { file: source, id: id as u32, offset: 0, mtime: entry.mtime, prev_tail: Vec::new() })
+        .collect();
+    repaired.chunks = create_segments(files, segments, target, index, header, threads)?;
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{source_of, temp_file};
+    use crate::create_files::{create_files, Source};
+    use crate::header::HASH_MULTIPLY;
+    use crate::index::IndexFile;
+
+    fn text() -> Vec<u8> {
+        (0..3000).flat_map(|i| format!("{} host{} value={}\n", i, i % 7, i * 31 % 1000).into_bytes()).collect()
+    }
+
+    // single-file archive of text as app.log
+    fn archive_of(name:&str, text:&[u8], header:Header) -> File {
+        let mut target = temp_file(&format!("{}-target", name));
+        let source = Source { name: "app.log".to_string(), ..source_of(name, text) };
+        create_files(&mut [source], &mut target, None, header, 2).unwrap();
+        target
+    }
+
+    fn expanded(target:&File) -> Vec<u8> {
+        let index_file = IndexFile::open_embedded(target).unwrap().unwrap();
+        let mut out = Vec::new();
+        crate::expand_files::expand_file(target, &index_file, true, &mut out).unwrap();
+        out
+    }
+
+    // the index as bytes, to compare a rebuilt index with the one create wrote
+    fn index_bytes(target:&File) -> String {
+        let index_file = IndexFile::open_embedded(target).unwrap().unwrap();
+        let list: ListofIndex = rkyv::Deserialize::deserialize(index_file.list(), &mut rkyv::Infallible).unwrap_or_else(|e| match e {});
+        format!("{:?}", list)
+    }
+
+    #[test]
+    fn test_repair() {
+        let text = text();
+        let split = 20000;
+        let mut target = temp_file("repair-target");
+        let header = Header { delimiter: None, folded: true, ..Header::new(1024) };
+        create_files(&mut [source_of("repair-a", &text[..split]), source_of("repair-b", &text[split..])], &mut target, None, header, 2).unwrap();
+        let created = index_bytes(&target);
+
+        // the embedded index is rebuilt as it was
+        let repaired = repair_archive(&mut target, None).unwrap();
+        assert_eq!((repaired.skipped, repaired.chunks), (0, IndexFile::open_embedded(&target).unwrap().unwrap().list().n as usize));
+        assert_eq!(index_bytes(&target), created);
+
+        // a create killed in the middle of a chunk, without index
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        let last = &index_file.list().indexies[10];
+        let torn = last.offset + last.compress_size as u64 / 2;
+        drop(index_file);
+        target.set_len(torn).unwrap();
+        let repaired = repair_archive(&mut target, None).unwrap();
+        assert_eq!((repaired.chunks, repaired.skipped), (10, 0));
+        assert!(repaired.truncated > 0);
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        assert_eq!(index_file.list().n, 10);
+        assert_eq!(index_file.list().files[0].size, index_file.list().indexies.iter().map(|ielm| ielm.original_size as u64).sum::<u64>());
+        assert!(crate::verify::verify_chunks(&target, &index_file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_repair_damaged_chunk() {
+        let mut target = archive_of("damaged", &text(), Header::new(1024));
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        let n = index_file.list().n as usize;
+        let damaged = &index_file.list().indexies[3];
+        let lost = damaged.file_offset;
+        target.write_all_at(&[0u8; 32], damaged.offset - 20).unwrap();
+        drop(index_file);
+
+        // chunk 3 is lost, the chunks after it are found again
+        let repaired = repair_archive(&mut target, None).unwrap();
+        assert_eq!((repaired.chunks, repaired.skipped), (n - 1, 1));
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        assert!(index_file.list().indexies.iter().all(|ielm| ielm.file_offset != lost));
+    }
+
+    #[test]
+    fn test_next_chunk_at_window_boundary() {
+        let target = archive_of("boundary", &text(), Header::new(1024));
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        let chunk = &index_file.list().indexies[3];
+        let start = chunk.offset - (FRAME_SIZE + "app.log".len()) as u64;
+        let next = chunk.offset + chunk.compress_size as u64;
+        let len = target.metadata().unwrap().len();
+
+        // the first window ends inside the magic of the next frame, the second one starts with it
+        let window = (next - start) as usize + FRAME_MAGIC.len() - 1;
+        assert_eq!(next_chunk(&target, start, len, index_file.header, window).unwrap(), Some(next));
+        assert_eq!(next_chunk(&target, start, len, index_file.header, SCAN_WINDOW).unwrap(), Some(next));
+    }
+
+    #[test]
+    fn test_reindex() {
+        let text = text();
+        let settings = Header { folded: true, hash_fn: HASH_MULTIPLY, ngram: 4, hash_bits: 12, ..Header::new(1024) };
+        let created = index_bytes(&archive_of("reindex-created", &text, settings));
+
+        // the same index as an archive created with the new settings
+        let mut target = archive_of("reindex", &text, Header::new(1024));
+        let repaired = reindex_archive(&mut target, None, Header { chunk_size: 4096, ..settings }).unwrap();
+        assert_eq!(repaired.skipped, 0);
+        assert_eq!(IndexFile::open_embedded(&target).unwrap().unwrap().header, settings);
+        assert_eq!(index_bytes(&target), created);
+        assert_eq!(expanded(&target), text);
+    }
+
+    #[test]
+    fn test_reindex_syslog_year() {
+        // syslog lines of an old archive keep their year, it comes from the modification time of the source
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1551398400); // 2019-03-01
+        let text: Vec<u8> = (0..600).flat_map(|i| format!("{} {:02} 10:00:00 host n={}\n", ["Dec", "Jan", "Feb"][i / 200], i % 200 / 8 + 1, i).into_bytes()).collect();
+        let archive = |name:&str, header:Header| {
+            let mut target = temp_file(&format!("{}-target", name));
+            let source = Source { name: "app.log".to_string(), ..source_of(name, &text) };
+            source.file.set_modified(mtime).unwrap();
+            create_files(&mut [source], &mut target, None, header, 2).unwrap();
+            target
+        };
+        let settings = Header { time_format: Some(crate::timestamp::TimeFormat::Syslog), ..Header::new(1024) };
+        let created = archive("syslog-created", settings);
+        let index_file = IndexFile::open_embedded(&created).unwrap().unwrap();
+        assert_eq!(index_file.list().indexies[0].min_time, 1543658400); // 2018-12-01T10:00:00Z
+        drop(index_file);
+
+        let mut target = archive("syslog", Header::new(1024));
+        reindex_archive(&mut target, None, settings).unwrap();
+        assert_eq!(index_bytes(&target), index_bytes(&created));
+    }
+
+    #[test]
+    fn test_rechunk() {
+        let text = text();
+        let archive = archive_of("rechunk", &text, Header::new(1024));
+        let settings = Header { delimiter: None, ..Header::new(4096) };
+        let created = index_bytes(&archive_of("rechunk-created", &text, settings));
+
+        let mut target = temp_file("rechunk-new");
+        let repaired = rechunk_archive(&archive, &mut target, None, settings, 2).unwrap();
+        assert_eq!(repaired.chunks, text.len().div_ceil(4096));
+        assert_eq!(index_bytes(&target), created);
+        assert_eq!(expanded(&target), text);
+    }
+}
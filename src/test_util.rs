@@ -0,0 +1,25 @@
+// fixtures shared by the test modules
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::create_files::Source;
+
+// a read-write file already unlinked from the temp dir
+// names are numbered, tests of several modules run at once
+pub fn temp_file(name:&str) -> File {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("mmsearch-test-{}-{}-{}", name, std::process::id(), SEQ.fetch_add(1, Ordering::Relaxed)));
+    let f = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    f
+}
+
+// source file name.log holding text, positioned at its start
+// its modification time is pinned, archives of the same sources are the same bytes
+pub fn source_of(name:&str, text:&[u8]) -> Source {
+    let mut file = temp_file(name);
+    file.write_all(text).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.set_modified(std::time::UNIX_EPOCH).unwrap();
+    Source { name: format!("{}.log", name), file }
+}
@@ -0,0 +1,365 @@
+use crate::error::{Error, Result};
+
+// how the timestamp of a log line is written
+//
+//  Rfc3339  2023-10-11T22:14:15.003Z, 2023-10-11 22:14:15+09:00 at line start, optionally in [ ]
+//  Syslog   Oct 11 22:14:15 at line start, optionally after <PRI>, the year is the latest one not after a reference time
+//  Apache   [10/Oct/2023:13:55:36 -0700], the first [ of the line
+//  Epoch    1697062455, 1697062455.003 or milliseconds 1697062455003 at line start
+//
+// timestamps are seconds since the epoch, a time without zone is UTC
+// the reference time of syslog lines is the modification time of their source when they are archived,
+// and the largest time of their chunk when they are searched, so their year never depends on the clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeFormat {
+    Rfc3339,
+    Syslog,
+    Apache,
+    Epoch,
+}
+
+pub const TIME_FORMATS: [&str; 4] = ["rfc3339", "syslog", "apache", "epoch"];
+
+const MONTHS: [&[u8; 3]; 12] = [b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec"];
+
+impl TimeFormat {
+    pub fn from_name(name:&str) -> Result<TimeFormat> {
+        match name {
+            "rfc3339" => Ok(TimeFormat::Rfc3339),
+            "syslog" => Ok(TimeFormat::Syslog),
+            "apache" => Ok(TimeFormat::Apache),
+            "epoch" => Ok(TimeFormat::Epoch),
+            _ => Err(Error::InvalidInput(format!("unknown timestamp format {}, expected one of {:?}", name, TIME_FORMATS))),
+        }
+    }
+
+    // id in the index header, 0 is no timestamp index
+    pub fn id(self) -> u8 {
+        match self {
+            TimeFormat::Rfc3339 => 1,
+            TimeFormat::Syslog => 2,
+            TimeFormat::Apache => 3,
+            TimeFormat::Epoch => 4,
+        }
+    }
+
+    pub fn from_id(id:u8) -> Option<TimeFormat> {
+        match id {
+            1 => Some(TimeFormat::Rfc3339),
+            2 => Some(TimeFormat::Syslog),
+            3 => Some(TimeFormat::Apache),
+            4 => Some(TimeFormat::Epoch),
+            _ => None,
+        }
+    }
+
+    // timestamp of line, None when the line has none, e.g. a continuation line
+    // a line without year is not after latest
+    pub fn parse_line(self, line:&[u8], latest:i64) -> Option<i64> {
+        match self {
+            TimeFormat::Rfc3339 => parse_rfc3339(line.strip_prefix(b"[").unwrap_or(line)),
+            TimeFormat::Syslog => parse_syslog(skip_pri(line), latest),
+            TimeFormat::Apache => parse_apache(&line[line.iter().position(|x| *x==b'[')? + 1..]),
+            TimeFormat::Epoch => parse_epoch(line),
+        }
+    }
+}
+
+// search window of --since and --until, from since up to, not including, until
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub since: i64,
+    pub until: i64,
+}
+
+impl TimeWindow {
+    pub fn new(since:Option<i64>, until:Option<i64>) -> TimeWindow {
+        TimeWindow { since: since.unwrap_or(i64::MIN), until: until.unwrap_or(i64::MAX) }
+    }
+
+    pub fn contains(self, t:i64) -> bool {
+        self.since <= t && t < self.until
+    }
+
+    // a chunk with lines from min to max may have lines in the window, min > max is an unknown range
+    pub fn overlaps(self, min:i64, max:i64) -> bool {
+        min > max || (max >= self.since && min < self.until)
+    }
+
+    // every line of a chunk with lines from min to max is in the window
+    pub fn covers(self, min:i64, max:i64) -> bool {
+        min <= max && self.contains(min) && self.contains(max)
+    }
+}
+
+// lines of a chunk partly outside the window are checked one by one
+// a line without timestamp has the one of the line before, lines before the first timestamp have lead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineFilter {
+    pub window: TimeWindow,
+    pub format: TimeFormat,
+    pub lead: Option<i64>,
+    pub latest: i64, // largest time of the chunk, lines without year are not after it
+}
+
+impl LineFilter {
+    // lines of chunk, given by start and end in order, which are in the window, unknown times are kept
+    pub fn filter_lines(self, chunk:&[u8], lines:Vec<(usize,usize)>, eol:u8) -> Vec<(usize,usize)> {
+        let mut current = self.lead;
+        let mut pos = 0;
+        lines.into_iter().filter(|(start, _)| {
+            while pos <= *start && pos < chunk.len() {
+                let end = chunk[pos..].iter().position(|x| *x==eol).map_or(chunk.len(), |i| pos + i + 1);
+                if let Some(t) = self.format.parse_line(&chunk[pos..end], self.latest) {
+                    current = Some(t);
+                }
+                pos = end;
+            }
+            current.is_none_or(|t| self.window.contains(t))
+        }).collect()
+    }
+}
+
+// whether chunk starts with lines without timestamp, continuing a record of the chunk before
+pub fn continues_record(chunk:&[u8], eol:u8, format:TimeFormat) -> bool {
+    let first = chunk.split(|x| *x==eol).next().unwrap_or_default();
+    // whether the line has a timestamp does not depend on its year
+    !chunk.is_empty() && format.parse_line(first, 0).is_none()
+}
+
+// latest time of the lines of a source modified at mtime, a day later for clock skew and local times taken as UTC
+pub fn latest_of(mtime:i64) -> i64 {
+    mtime.saturating_add(86400)
+}
+
+// smallest and largest timestamp of the lines of chunk, lines without year are not after latest
+pub fn time_range(chunk:&[u8], eol:u8, format:TimeFormat, latest:i64) -> Option<(i64, i64)> {
+    chunk.split(|x| *x==eol).filter_map(|line| format.parse_line(line, latest))
+        .fold(None, |range, t| match range {
+            None => Some((t, t)),
+            Some((min, max)) => Some((min.min(t), max.max(t))),
+        })
+}
+
+// --since and --until: RFC3339 time, a date YYYY-MM-DD, or seconds since the epoch
+pub fn parse_time_arg(s:&str) -> Result<i64> {
+    let bytes = s.as_bytes();
+    let time = if bytes.len()==10 && bytes.get(4)==Some(&b'-') {
+        parse_date(bytes).map(|days| days * 86400)
+    } else if bytes.iter().all(|x| x.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        parse_rfc3339(bytes)
+    };
+    time.ok_or_else(|| Error::InvalidInput(format!("invalid time {}, expected RFC3339, YYYY-MM-DD or epoch seconds", s)))
+}
+
+// days since 1970-01-01 of a civil date
+fn days_from_civil(year:i64, month:i64, day:i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn number(bytes:&[u8]) -> Option<i64> {
+    if bytes.is_empty() || !bytes.iter().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    // a run of digits too long for an i64 is no number
+    bytes.iter().try_fold(0i64, |n, x| n.checked_mul(10)?.checked_add((x - b'0') as i64))
+}
+
+fn valid(month:i64, day:i64, hour:i64, minute:i64, second:i64) -> bool {
+    (1..=12).contains(&month) && (1..=31).contains(&day) && hour < 24 && minute < 60 && second <= 60
+}
+
+// YYYY-MM-DD in days since the epoch
+fn parse_date(b:&[u8]) -> Option<i64> {
+    if b.len() < 10 || b[4]!=b'-' || b[7]!=b'-' {
+        return None;
+    }
+    let (year, month, day) = (number(&b[0..4])?, number(&b[5..7])?, number(&b[8..10])?);
+    if !valid(month, day, 0, 0, 0) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+// HH:MM:SS in seconds
+fn parse_clock(b:&[u8]) -> Option<i64> {
+    if b.len() < 8 || b[2]!=b':' || b[5]!=b':' {
+        return None;
+    }
+    let (hour, minute, second) = (number(&b[0..2])?, number(&b[3..5])?, number(&b[6..8])?);
+    if !valid(1, 1, hour, minute, second) {
+        return None;
+    }
+    Some(hour * 3600 + minute * 60 + second)
+}
+
+// +HH:MM, +HHMM or Z in seconds east of UTC
+fn parse_zone(b:&[u8]) -> Option<i64> {
+    match b.first() {
+        Some(b'Z') | Some(b'z') => Some(0),
+        Some(sign @ (b'+' | b'-')) => {
+            let sign = if *sign==b'+' { 1 } else { -1 };
+            let hours = number(b.get(1..3)?)?;
+            let minutes = match b.get(3) {
+                Some(b':') => number(b.get(4..6)?)?,
+                _ => number(b.get(3..5)?)?,
+            };
+            Some(sign * (hours * 3600 + minutes * 60))
+        },
+        _ => None,
+    }
+}
+
+fn parse_rfc3339(b:&[u8]) -> Option<i64> {
+    let days = parse_date(b)?;
+    if !matches!(b.get(10), Some(b'T') | Some(b't') | Some(b' ')) {
+        return None;
+    }
+    let clock = parse_clock(b.get(11..)?)?;
+    let mut rest = &b[19..];
+    while rest.first().is_some_and(|x| *x==b'.' || *x==b',' || x.is_ascii_digit()) {
+        rest = &rest[1..];
+    }
+    let zone = parse_zone(rest).unwrap_or(0);
+    Some(days * 86400 + clock - zone)
+}
+
+fn skip_pri(line:&[u8]) -> &[u8] {
+    if line.first()==Some(&b'<') {
+        if let Some(end) = line.iter().position(|x| *x==b'>') {
+            return &line[end + 1..];
+        }
+    }
+    line
+}
+
+fn parse_month(b:&[u8]) -> Option<i64> {
+    MONTHS.iter().position(|m| b.get(0..3)==Some(&m[..])).map(|m| m as i64 + 1)
+}
+
+// Mmm dd HH:MM:SS, in the latest year which does not put it after latest
+fn parse_syslog(b:&[u8], latest:i64) -> Option<i64> {
+    let month = parse_month(b)?;
+    if b.get(3)!=Some(&b' ') {
+        return None;
+    }
+    let day = number(b.get(4..6)?.trim_ascii_start())?;
+    if b.get(6)!=Some(&b' ') || !valid(month, day, 0, 0, 0) {
+        return None;
+    }
+    let clock = parse_clock(b.get(7..)?)?;
+    let year = 1970 + latest.div_euclid(86400 * 365);
+    // the year estimate from latest is at most one off
+    (year - 1..=year + 1).rev()
+        .filter_map(|year| days_from_civil(year, month, day).checked_mul(86400)?.checked_add(clock))
+        .find(|t| *t <= latest)
+}
+
+// dd/Mon/yyyy:HH:MM:SS +zzzz
+fn parse_apache(b:&[u8]) -> Option<i64> {
+    if b.len() < 20 || b[2]!=b'/' || b[6]!=b'/' || b[11]!=b':' {
+        return None;
+    }
+    let (day, month, year) = (number(&b[0..2])?, parse_month(&b[3..6])?, number(&b[7..11])?);
+    if !valid(month, day, 0, 0, 0) {
+        return None;
+    }
+    let clock = parse_clock(&b[12..])?;
+    let zone = b.get(20..).and_then(|z| parse_zone(z.trim_ascii_start())).unwrap_or(0);
+    Some(days_from_civil(year, month, day) * 86400 + clock - zone)
+}
+
+// seconds, seconds with fraction, or milliseconds
+fn parse_epoch(b:&[u8]) -> Option<i64> {
+    let digits = b.iter().position(|x| !x.is_ascii_digit()).unwrap_or(b.len());
+    let n = number(&b[0..digits])?;
+    match digits {
+        9..=11 => Some(n),
+        12..=14 => Some(n / 1000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2023, 10, 11), 19641);
+    }
+
+    #[test]
+    fn test_formats() {
+        let t = 1697062455; // 2023-10-11T22:14:15Z
+        assert_eq!(TimeFormat::Rfc3339.parse_line(b"2023-10-11T22:14:15.003Z INFO start", t), Some(t));
+        assert_eq!(TimeFormat::Rfc3339.parse_line(b"[2023-10-12 07:14:15+09:00] INFO", t), Some(t));
+        assert_eq!(TimeFormat::Rfc3339.parse_line(b"  at java.lang.Thread.run", t), None);
+        assert_eq!(parse_syslog(b"Oct 11 22:14:15 host sshd[1]: ok", t + 3600), Some(t));
+        assert_eq!(parse_syslog(b"Oct  1 00:00:00 host", t), Some(t - 10 * 86400 - 22 * 3600 - 14 * 60 - 15));
+        // December lines read in January are from the last year
+        assert_eq!(parse_syslog(b"Dec 31 23:59:59 host", days_from_civil(2024, 1, 1) * 86400), Some(days_from_civil(2024, 1, 1) * 86400 - 1));
+        assert_eq!(TimeFormat::Apache.parse_line(b"127.0.0.1 - - [11/Oct/2023:15:14:15 -0700] \"GET / HTTP/1.1\"", t), Some(t));
+        assert_eq!(TimeFormat::Epoch.parse_line(b"1697062455 start", t), Some(t));
+        assert_eq!(TimeFormat::Epoch.parse_line(b"1697062455003 start", t), Some(t));
+        assert_eq!(TimeFormat::Epoch.parse_line(b"1697062455.5 start", t), Some(t));
+        assert_eq!(TimeFormat::Epoch.parse_line(b"42 start", t), None);
+        assert_eq!(TimeFormat::Epoch.parse_line(b"1234567890123456789012345 start", t), None);
+    }
+
+    #[test]
+    fn test_syslog_year() {
+        // the year comes from the reference time, whatever the clock says
+        let latest = latest_of(days_from_civil(2019, 3, 1) * 86400);
+        let chunk = b"Dec 30 10:00:00 host a\nJan  2 10:00:00 host b\nFeb 28 10:00:00 host c\n";
+        let (dec, feb) = (days_from_civil(2018, 12, 30) * 86400 + 36000, days_from_civil(2019, 2, 28) * 86400 + 36000);
+        assert_eq!(time_range(chunk, b'\n', TimeFormat::Syslog, latest), Some((dec, feb)));
+        // searching with the largest time of the chunk gives the same years
+        let lines = chunk.split_inclusive(|x| *x==b'\n').scan(0, |pos, line| { *pos += line.len(); Some((*pos - line.len(), *pos)) }).collect::<Vec<_>>();
+        let window = TimeWindow::new(Some(days_from_civil(2019, 1, 1) * 86400), None);
+        let filter = LineFilter { window, format: TimeFormat::Syslog, lead: None, latest: feb };
+        assert_eq!(filter.filter_lines(chunk, lines.clone(), b'\n'), lines[1..].to_vec());
+        // a line a little after the modification time is still in its year
+        assert_eq!(parse_syslog(b"Mar  1 10:00:00 host", latest), Some(days_from_civil(2019, 3, 1) * 86400 + 36000));
+    }
+
+    #[test]
+    fn test_time_range() {
+        let chunk = b"2023-10-11T22:14:15Z a\n  continued\n2023-10-11T22:14:10Z b\n2023-10-11T22:15:00Z c\n";
+        assert_eq!(time_range(chunk, b'\n', TimeFormat::Rfc3339, 0), Some((1697062450, 1697062500)));
+        assert_eq!(time_range(b"no time\n", b'\n', TimeFormat::Rfc3339, 0), None);
+    }
+
+    #[test]
+    fn test_window() {
+        let window = TimeWindow::new(Some(100), Some(200));
+        assert!(window.overlaps(50, 100) && !window.overlaps(50, 99) && !window.overlaps(200, 300));
+        assert!(window.overlaps(i64::MAX, i64::MIN));
+        assert!(window.covers(100, 199) && !window.covers(100, 200) && !window.covers(i64::MAX, i64::MIN));
+        let chunk = b"  lost\n1970-01-01T00:01:39Z a\n  at x\n1970-01-01T00:02:30Z b\n  at y\n1970-01-01T00:03:20Z c\n";
+        let lines = chunk.split_inclusive(|x| *x==b'\n').scan(0, |pos, line| { *pos += line.len(); Some((*pos - line.len(), *pos)) }).collect::<Vec<_>>();
+        let filter = LineFilter { window, format: TimeFormat::Rfc3339, lead: None, latest: 200 };
+        assert_eq!(filter.filter_lines(chunk, lines.clone(), b'\n'), vec![lines[0], lines[3], lines[4]]);
+        let filter = LineFilter { lead: Some(99), ..filter };
+        assert_eq!(filter.filter_lines(chunk, lines.clone(), b'\n'), vec![lines[3], lines[4]]);
+        assert!(continues_record(chunk, b'\n', TimeFormat::Rfc3339));
+        assert!(!continues_record(&chunk[7..], b'\n', TimeFormat::Rfc3339));
+    }
+
+    #[test]
+    fn test_parse_time_arg() {
+        assert_eq!(parse_time_arg("2023-10-11").unwrap(), 1697068800 - 86400);
+        assert_eq!(parse_time_arg("2023-10-11T22:14:15Z").unwrap(), 1697062455);
+        assert_eq!(parse_time_arg("1697062455").unwrap(), 1697062455);
+        assert!(parse_time_arg("yesterday").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use memchr::memchr_iter;
+use crate::error::{Error, Result};
+use crate::header::{Header, FRAME_SIZE, HEADER_SIZE};
+use crate::index::{checksum, expand_chunk, ArchivedIndex, ChunkBits, Index, IndexFile};
+use crate::repair::read_frame;
+
+// checksums of a chunk recorded in its index entry
+#[derive(Debug, Clone, Copy)]
+pub struct Checksums {
+    pub compressed: u32,
+    pub original: u32,
+}
+
+impl Checksums {
+    pub fn of(ielm:&ArchivedIndex) -> Checksums {
+        Checksums { compressed: ielm.compressed_checksum, original: ielm.original_checksum }
+    }
+
+    // compressed bytes of chunk at offset, checked before they are decompressed
+    pub fn check_compressed(self, chunk:usize, offset:u64, bytes:&[u8]) -> Result<()> {
+        if checksum(bytes) != self.compressed {
+            return Err(Error::Checksum { chunk, offset, message: "compressed bytes do not match their checksum".to_string() });
+        }
+        Ok(())
+    }
+
+    pub fn check_original(self, chunk:usize, offset:u64, bytes:&[u8]) -> Result<()> {
+        if checksum(bytes) != self.original {
+            return Err(Error::Checksum { chunk, offset, message: "decompressed bytes do not match their checksum".to_string() });
+        }
+        Ok(())
+    }
+}
+
+// check a chunk against its index entry: checksums, sizes, line count and bitmaps
+// bitmaps may hold more bits than the chunk, the n-grams across a cut inside a record, but none less
+fn verify_chunk(id:usize, ielm:&ArchivedIndex, compressed:&[u8], chunk:&mut Vec<u8>, header:Header) -> Result<()> {
+    let offset = ielm.offset;
+    let checksums = Checksums::of(ielm);
+    checksums.check_compressed(id, offset, compressed)?;
+    chunk.resize(ielm.original_size as usize, 0);
+    let size = expand_chunk(compressed, ielm.raw, chunk)
+        .map_err(|message| Error::Decompress { chunk: id, offset, message })?;
+    if size != chunk.len() {
+        return Err(Error::Decompress { chunk: id, offset, message: format!("{} bytes expanded, index says {}", size, chunk.len()) });
+    }
+    checksums.check_original(id, offset, chunk)?;
+
+    let corrupt = |msg:String| Error::CorruptIndex(format!("chunk {} at offset {}: {}", id, offset, msg));
+    let eol = header.delimiter.unwrap_or(b'\n');
+    let lines = memchr_iter(eol, chunk).count();
+    if lines != ielm.lines as usize {
+        return Err(corrupt(format!("{} lines, index says {}", lines, ielm.lines)));
+    }
+    // searches read on into the next chunk for the end of the last line of a cut chunk
+    let cut = chunk.last().is_some_and(|b| *b != eol);
+    if cut != ielm.cut {
+        return Err(corrupt(format!("ends inside a line: {}, index says {}", cut, ielm.cut)));
+    }
+    let ngrams = header.ngrams();
+    let mut recomputed = if header.folded { Index::new_folded(ngrams, 0, 0, 0) } else { Index::new(ngrams, 0, 0, 0) };
+    recomputed.fill(ngrams, chunk);
+    let bitmaps = [
+        ("n-gram", ielm.trigram(), recomputed.trigram()),
+        ("byte", ielm.unigram(), recomputed.unigram()),
+        ("bigram", ielm.bigram(), recomputed.bigram()),
+        ("case folded n-gram", ielm.folded(), recomputed.folded()),
+    ];
+    for (name, stored, computed) in bitmaps {
+        if stored.len() != computed.len() || stored.iter().zip(computed).any(|(s, c)| c & !s != 0) {
+            return Err(corrupt(format!("{} bitmap misses n-grams of the chunk", name)));
+        }
+    }
+    Ok(())
+}
+
+// read every chunk of the archive and check it against the index, returns the problems found
+// every chunk has to follow the chunk before behind the frame of its file, the manifest sizes have to add up
+// a gap between chunks is left by repair in place of a damaged chunk
+pub fn verify_chunks(archive:&File, index_file:&IndexFile) -> Result<Vec<Error>> {
+    let header = index_file.header;
+    let list = index_file.list();
+    let len = archive.metadata()?.len();
+    let mut problems = Vec::new();
+    let mut compressed = Vec::new();
+    let mut chunk = Vec::new();
+    let mut end = HEADER_SIZE as u64; // end of the chunk before
+
+    for (id, ielm) in list.indexies.iter().enumerate() {
+        let name = list.files.get(ielm.file as usize).map_or("", |file| file.name.as_str());
+        let frame_pos = ielm.offset.checked_sub((FRAME_SIZE + name.len().min(u16::MAX as usize)) as u64).filter(|&pos| pos >= end);
+        let frame = match frame_pos {
+            Some(pos) => read_frame(archive, pos, len)?,
+            None => None,
+        };
+        let matches = frame.is_some_and(|frame| frame.name==name && frame.file==ielm.file && frame.file_offset==ielm.file_offset
+            && frame.compress_size==ielm.compress_size && frame.original_size==ielm.original_size
+            && frame.compressed_checksum==ielm.compressed_checksum && frame.original_checksum==ielm.original_checksum);
+        if !matches {
+            problems.push(Error::CorruptIndex(format!("chunk {} at offset {}: no frame of the chunk in front of it", id, ielm.offset)));
+        }
+        end = ielm.offset + ielm.compress_size as u64;
+        compressed.resize(ielm.compress_size as usize, 0);
+        match archive.read_exact_at(&mut compressed, ielm.offset) {
+            Ok(()) => {},
+            Err(e) if e.kind()==ErrorKind::UnexpectedEof => {
+                problems.push(Error::CorruptIndex(format!("chunk {} at offset {}: archive is shorter than its index", id, ielm.offset)));
+                break;
+            },
+            Err(e) => return Err(e.into()),
+        }
+        if let Err(e) = verify_chunk(id, ielm, &compressed, &mut chunk, header) {
+            problems.push(e);
+        }
+    }
+    for (id, file) in list.files.iter().enumerate() {
+        let size: u64 = list.indexies.iter().filter(|ielm| ielm.file as usize==id).map(|ielm| ielm.original_size as u64).sum();
+        if size != file.size {
+            problems.push(Error::CorruptIndex(format!("manifest records {} bytes of {}, its chunks hold {}", file.size, file.name, size)));
+        }
+    }
+    log::info!("{} chunks verified, {} problems", list.n, problems.len());
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{source_of, temp_file};
+    use crate::create_files::create_files;
+
+    #[test]
+    fn test_verify() {
+        let text: Vec<u8> = (0..2000).flat_map(|i| format!("{} host{} value={}\n", i, i % 7, i * 31 % 1000).into_bytes()).collect();
+        let mut target = temp_file("verify-target");
+        create_files(&mut [source_of("verify", &text)], &mut target, None, Header { folded: true, ..Header::new(1024) }, 2).unwrap();
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        assert!(verify_chunks(&target, &index_file).unwrap().is_empty());
+
+        // one flipped bit of chunk 2 is found, the other chunks are fine
+        let ielm = &index_file.list().indexies[2];
+        let mut byte = [0u8];
+        target.read_exact_at(&mut byte, ielm.offset + 5).unwrap();
+        target.write_all_at(&[byte[0] ^ 0x10], ielm.offset + 5).unwrap();
+        let problems = verify_chunks(&target, &index_file).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(problems[0], Error::Checksum { chunk: 2, .. }));
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use crate::create_files::{append_archive, Source};
+use crate::error::{Error, Result};
+use crate::index::open_index;
+
+// a source file followed into an archive, through log rotations
+struct Follower {
+    source_path: PathBuf,
+    target_path: PathBuf,
+    index_path: PathBuf,
+    threads: usize,
+    chunk_size: u64,
+    source: Source,
+    inode: u64,
+    position: u64,  // bytes of source archived
+    attempted: u64, // source length at the last append, a partial line is not retried until it grows
+    drained: bool,  // source was renamed away and its last bytes are archived
+}
+
+impl Follower {
+    // continue the archive with source, or start a new segment when source was replaced while not followed
+    fn new(source_path:&Path, target_path:&Path, index_path:&Path, threads:usize) -> Result<Follower> {
+        let chunk_size = open_index(&fs::File::open(target_path)?, index_path)?.header.chunk_size as u64;
+        let source = Source::open(source_path)?;
+        let inode = source.file.metadata()?.ino();
+        let mut follower = Follower {
+            source_path: source_path.to_path_buf(),
+            target_path: target_path.to_path_buf(),
+            index_path: index_path.to_path_buf(),
+            threads, chunk_size, source, inode,
+            position: 0, attempted: 0, drained: false,
+        };
+        follower.position = match follower.append(false) {
+            Ok(position) => position,
+            Err(e @ Error::SourceMismatch(_)) => {
+                log::warn!("{}, archiving {} as a new segment", e, source_path.display());
+                follower.append(true)?
+            },
+            Err(e) => return Err(e),
+        };
+        Ok(follower)
+    }
+
+    fn append(&mut self, new_segment:bool) -> Result<u64> {
+        self.attempted = self.source.file.metadata()?.len();
+        append_archive(&mut self.source, &self.target_path, &self.index_path, self.threads, new_segment)
+    }
+
+    // archive new data of source when a chunk is filled, or when flush is set
+    // returns true when source_path is another file now and has to be watched again
+    fn check(&mut self, flush:bool) -> Result<bool> {
+        let len = self.source.file.metadata()?.len();
+        if len < self.position {
+            log::warn!("{} was truncated, archiving it as a new segment", self.source_path.display());
+            self.position = self.append(true)?;
+            return Ok(false);
+        }
+
+        // rename rotation, source_path is a new file or not created yet
+        let rotated = match fs::metadata(&self.source_path) {
+            Ok(metadata) => metadata.ino() != self.inode,
+            Err(e) if e.kind()==ErrorKind::NotFound => true,
+            Err(e) => return Err(e.into()),
+        };
+        if rotated {
+            if !self.drained {
+                // the bytes written to the old file before the rename
+                self.position = self.append(false)?;
+                self.drained = true;
+            }
+            let source = match Source::open(&self.source_path) {
+                Ok(source) => source,
+                Err(Error::Io(e)) if e.kind()==ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            log::info!("{} was rotated, archiving the new file as a new segment", self.source_path.display());
+            self.inode = source.file.metadata()?.ino();
+            self.source = source;
+            self.drained = false;
+            self.position = self.append(true)?;
+            return Ok(true);
+        }
+
+        if len > self.attempted && (len - self.position >= self.chunk_size || flush) {
+            self.position = match self.append(false) {
+                Ok(position) => position,
+                // truncated and grown beyond the archived length between two checks
+                Err(e @ Error::SourceMismatch(_)) => {
+                    log::warn!("{}, archiving {} as a new segment", e, self.source_path.display());
+                    self.append(true)?
+                },
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(false)
+    }
+}
+
+fn watch_source(inotify:&Inotify, source_path:&Path) -> Result<WatchDescriptor> {
+    let flags = AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_MOVE_SELF | AddWatchFlags::IN_DELETE_SELF | AddWatchFlags::IN_ATTRIB;
+    Ok(inotify.add_watch(source_path, flags)?)
+}
+
+// follow source_path and append its new lines to the archive at target_path every interval,
+// or as soon as a chunk is filled
+// the archive has to exist, its index is embedded or at index_path
+pub fn watch(source_path:&Path, target_path:&Path, index_path:&Path, interval:Duration, threads:usize) -> Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    let mut wd = watch_source(&inotify, source_path)?;
+    let mut follower = Follower::new(source_path, target_path, index_path, threads)?;
+    log::info!("watching {}, {} bytes archived", source_path.display(), follower.position);
+
+    let mut deadline = Instant::now() + interval;
+    loop {
+        // wake up on a change of source or at the flush deadline, rotation is noticed at the latest then
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(&inotify, PollFlags::POLLIN)];
+        poll(&mut fds, timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int)?;
+        match inotify.read_events() {
+            Ok(events) => log::debug!("{} inotify events", events.len()),
+            Err(nix::errno::Errno::EAGAIN) => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        let flush = Instant::now() >= deadline;
+        if follower.check(flush)? {
+            // the watch of a deleted file is already gone
+            let _ = inotify.rm_watch(wd);
+            wd = watch_source(&inotify, source_path)?;
+        }
+        if flush {
+            deadline = Instant::now() + interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use crate::create_files::create_empty;
+    use crate::header::Header;
+    use crate::index::{expand_chunk, IndexFile};
+
+    fn archived(target_path:&Path) -> Vec<u8> {
+        let target = fs::File::open(target_path).unwrap();
+        let index_file = IndexFile::open_embedded(&target).unwrap().unwrap();
+        index_file.list().indexies.iter().flat_map(|ielm| {
+            let mut compressed = vec![0u8; ielm.compress_size as usize];
+            target.read_exact_at(&mut compressed, ielm.offset).unwrap();
+            let mut original = vec![0u8; ielm.original_size as usize];
+            expand_chunk(&compressed, ielm.raw, &mut original).unwrap();
+            original
+        }).collect()
+    }
+
+    #[test]
+    fn test_follow_rotation() {
+        let dir = std::env::temp_dir().join(format!("mmsearch-test-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source_path = dir.join("app.log");
+        let target_path = dir.join("app.mms");
+        let mut source = fs::File::create(&source_path).unwrap();
+        source.write_all(b"one\ntwo\nthr").unwrap();
+        let mut target = fs::File::create(&target_path).unwrap();
+        create_empty(&mut target, None, Header::new(1024)).unwrap();
+
+        let mut follower = Follower::new(&source_path, &target_path, Path::new("unused"), 1).unwrap();
+        assert_eq!(archived(&target_path), b"one\ntwo\n");
+        // a flush of a partial line does not write the archive again
+        let written = |path:&Path| fs::metadata(path).map(|m| (m.ino(), m.modified().unwrap())).unwrap();
+        let before = written(&target_path);
+        source.write_all(b"ee").unwrap();
+        assert!(!follower.check(true).unwrap());
+        assert_eq!(written(&target_path), before);
+        // the partial line is archived when completed and flushed
+        source.write_all(b"\nfour\n").unwrap();
+        assert!(!follower.check(false).unwrap());
+        assert_eq!(archived(&target_path), b"one\ntwo\n");
+        // the index mapped by a search before an append stays as it was
+        let searched = fs::File::open(&target_path).unwrap();
+        let index_file = IndexFile::open_embedded(&searched).unwrap().unwrap();
+        assert!(!follower.check(true).unwrap());
+        assert_eq!(archived(&target_path), b"one\ntwo\nthree\nfour\n");
+        assert_eq!(index_file.list().files[0].size, 8);
+        assert!(IndexFile::open_embedded(&searched).unwrap().is_some());
+        drop(index_file);
+
+        // rename rotation, the last lines of the old file are kept
+        source.write_all(b"five\n").unwrap();
+        fs::rename(&source_path, dir.join("app.log.1")).unwrap();
+        assert!(!follower.check(true).unwrap());
+        assert_eq!(archived(&target_path), b"one\ntwo\nthree\nfour\nfive\n");
+        let mut source = fs::File::create(&source_path).unwrap();
+        source.write_all(b"six\n").unwrap();
+        assert!(follower.check(true).unwrap());
+        assert_eq!(archived(&target_path), b"one\ntwo\nthree\nfour\nfive\nsix\n");
+
+        // copy and truncate rotation
+        source.set_len(0).unwrap();
+        let mut source = fs::OpenOptions::new().append(true).open(&source_path).unwrap();
+        source.write_all(b"7\n").unwrap();
+        assert!(!follower.check(true).unwrap());
+        assert_eq!(archived(&target_path), b"one\ntwo\nthree\nfour\nfive\nsix\n7\n");
+
+        // a restart continues the current segment
+        source.write_all(b"8\n").unwrap();
+        Follower::new(&source_path, &target_path, Path::new("unused"), 1).unwrap();
+        assert_eq!(archived(&target_path), b"one\ntwo\nthree\nfour\nfive\nsix\n7\n8\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}